@@ -0,0 +1,94 @@
+//! Measures bulk throughput between a client and a server on loopback.
+//!
+//! Run with `cargo bench --features key-gen --bench loopback` and once more with
+//! `--features key-gen,udp-offload` to compare the batched Linux socket path.
+//!
+//! * `upload`: one stream from the client to the server.
+//! * `download`: one stream from the server to the client.
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_quicker::config;
+use tokio_quicker::connection::{Incoming, QuicConnection, ToClient, ToServer};
+use tokio_quicker::{QuicListener, QuicSocket};
+
+const BYTES: usize = 256 * 1024 * 1024;
+
+async fn connection_pair() -> (QuicConnection<ToServer>, QuicConnection<ToClient>) {
+    let mut listener =
+        QuicListener::bind_with_config("127.0.0.1:0", config::default(), vec![7; 16])
+            .await
+            .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut socket = QuicSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client, server) = tokio::join!(socket.connect(Some("localhost"), addr), listener.accept());
+    (client.unwrap(), server.unwrap())
+}
+
+async fn write_all(mut stream: impl AsyncWrite + Unpin) {
+    let chunk = vec![0x55; 64 * 1024];
+    for _ in 0..BYTES / chunk.len() {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    stream.shutdown().await.unwrap();
+}
+
+async fn read_all(mut stream: impl AsyncRead + Unpin) -> usize {
+    let mut buf = vec![0; 64 * 1024];
+    let mut received = 0;
+    loop {
+        match stream.read(&mut buf).await.unwrap() {
+            0 => return received,
+            read => received += read,
+        }
+    }
+}
+
+fn report(name: &str, bytes: usize, packets: usize, elapsed: Duration) {
+    println!(
+        "{name:>8}: {:.1} MiB/s, {packets} packets in {elapsed:.2?}",
+        bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64(),
+    );
+}
+
+async fn upload() {
+    let (mut client, mut server) = connection_pair().await;
+    let start = Instant::now();
+    let stream = client.uni(1).await.unwrap();
+    let writer = tokio::spawn(write_all(stream));
+    let Some(Incoming::Uni(incoming)) = server.incoming().await else {
+        panic!("Expected an incoming uni stream!");
+    };
+    let received = read_all(incoming).await;
+    let elapsed = start.elapsed();
+    writer.await.unwrap();
+    let packets = client.stats().await.unwrap().sent;
+    report("upload", received, packets, elapsed);
+}
+
+async fn download() {
+    let (mut client, mut server) = connection_pair().await;
+    let start = Instant::now();
+    let stream = server.uni(1).await.unwrap();
+    let writer = tokio::spawn(write_all(stream));
+    let Some(Incoming::Uni(incoming)) = client.incoming().await else {
+        panic!("Expected an incoming uni stream!");
+    };
+    let received = read_all(incoming).await;
+    let elapsed = start.elapsed();
+    writer.await.unwrap();
+    let packets = server.stats().await.unwrap().sent;
+    report("download", received, packets, elapsed);
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        upload().await;
+        download().await;
+    });
+}
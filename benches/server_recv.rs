@@ -0,0 +1,172 @@
+//! Measures the receive path of a `QuicListener` on loopback.
+//!
+//! Run with `cargo bench --features key-gen --bench server_recv`.
+//!
+//! * `dropped`: datagrams for an unknown connection, which the listener drops right away.
+//!   This isolates the socket loop of the listener.
+//! * `stream`: a single stream carrying bulk data from the client to the server,
+//!   which goes through the whole receive path.
+//!
+//! Allocations are counted for the whole process, so for `stream` they include the client.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio_quicker::config::{self, Settings};
+use tokio_quicker::connection::Incoming;
+use tokio_quicker::metrics::{DropReason, Metrics};
+use tokio_quicker::{QuicListener, QuicSocket};
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[derive(Default)]
+struct CountDropped(AtomicUsize);
+
+impl Metrics for CountDropped {
+    fn packet_dropped(&self, _reason: DropReason) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn report(name: &str, packets: usize, elapsed: Duration, allocations: usize) {
+    println!(
+        "{name:>8}: {packets} packets in {elapsed:.2?}, {:.0} packets/s, {:.2} allocations/packet",
+        packets as f64 / elapsed.as_secs_f64(),
+        allocations as f64 / packets as f64,
+    );
+}
+
+async fn dropped() {
+    const PACKETS: usize = 200_000;
+
+    let metrics = Arc::new(CountDropped::default());
+    let settings = Settings {
+        metrics: metrics.clone(),
+        ..Default::default()
+    };
+    let listener =
+        QuicListener::bind_with_settings("127.0.0.1:0", config::default(), vec![7; 16], settings)
+            .await
+            .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    // Short header packet, its destination connection id is not known to the listener.
+    let mut packet = [0x55; 1200];
+    packet[0] = 0x40;
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let sender = tokio::spawn(async move {
+        for _ in 0..PACKETS {
+            socket.send_to(&packet, addr).await.unwrap();
+        }
+    });
+
+    // Loopback drops datagrams once the socket buffer is full,
+    // so wait until either everything arrived or nothing arrives anymore.
+    let mut received = 0;
+    let mut last_progress = Instant::now();
+    loop {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let now = metrics.0.load(Ordering::Relaxed);
+        if now != received {
+            received = now;
+            last_progress = Instant::now();
+        }
+        if received == PACKETS || last_progress.elapsed() > Duration::from_millis(200) {
+            break;
+        }
+    }
+    let elapsed = last_progress - start;
+    sender.await.unwrap();
+    report(
+        "dropped",
+        received,
+        elapsed,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+    );
+}
+
+async fn stream() {
+    const BYTES: usize = 64 * 1024 * 1024;
+
+    let mut listener =
+        QuicListener::bind_with_config("127.0.0.1:0", config::default(), vec![7; 16])
+            .await
+            .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut socket = QuicSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client, server) = tokio::join!(socket.connect(Some("localhost"), addr), listener.accept());
+    let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let writer = tokio::spawn(async move {
+        let chunk = vec![0x55; 64 * 1024];
+        let mut stream = client.uni(1).await.unwrap();
+        for _ in 0..BYTES / chunk.len() {
+            stream.write_all(&chunk).await.unwrap();
+        }
+        stream.shutdown().await.unwrap();
+        client
+    });
+
+    let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+        panic!("Expected an incoming uni stream!");
+    };
+    let mut buf = vec![0; 64 * 1024];
+    let mut received = 0;
+    while received < BYTES {
+        match incoming.read(&mut buf).await.unwrap() {
+            0 => break,
+            read => received += read,
+        }
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let _client = writer.await.unwrap();
+
+    let packets = server.stats().await.unwrap().recv;
+    report("stream", packets, elapsed, allocations);
+    println!(
+        "{:>8}  {:.1} MiB/s",
+        "",
+        received as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        dropped().await;
+        stream().await;
+    });
+}
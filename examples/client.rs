@@ -17,11 +17,7 @@ async fn main() -> Result<()> {
     stream.write(b"./Cargo.toml").await?;
 
     let mut buf: Vec<u8> = Vec::with_capacity(u16::MAX as usize * 4);
-    loop {
-        if let Err(_) = stream.read_buf(&mut buf).await {
-            break;
-        }
-    }
+    stream.read_to_end(&mut buf).await?;
     println!("{}", String::from_utf8_lossy(&buf));
     stream.shutdown().await?;
     Ok(())
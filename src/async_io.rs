@@ -1,62 +1,28 @@
 use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use bytes::BufMut;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::Receiver;
+use crate::config::STREAM_BUFFER_SIZE;
 use crate::Message;
-use crate::stream::{BidiStream, Readable, UniStream, Writeable};
+use crate::stream::{buffer_message, BidiStream, Readable, StreamSender, UniStream, Writeable};
 
 impl AsyncRead for BidiStream {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        match self.rx.poll_recv(cx) {
-            Poll::Ready(Some(message)) => match message {
-                Ok(Message::Data {
-                       stream_id: _,
-                       bytes,
-                       fin,
-                   }) => {
-                    if fin {
-                        self.rx.close();
-                    }
-                    self.buffer_read.extend_from_slice(&bytes);
-                    let read_amount = buf.remaining_mut().min(self.buffer_read.len());
-                    buf.put_slice(&self.buffer_read[..read_amount]);
-                    buf.set_filled(read_amount);
-                    self.buffer_read.rotate_left(read_amount);
-                    let truncate_len = self.buffer_read.len() - read_amount;
-                    self.buffer_read.truncate(truncate_len);
-                    Poll::Ready(Ok(()))
-                }
-                Ok(Message::Close(_id)) => {
-                    self.rx.close();
-                    Poll::Ready(Ok(()))
-                },
-                Err(err) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err.to_string()))),
-            },
-            Poll::Ready(None) => Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "No new data is available to be read, stream is closed!",
-            ))),
-            Poll::Pending => Poll::Pending,
-        }
+        let this = &mut *self;
+        poll_read_buffered(&mut this.rx, &mut this.buffer_read, &mut this.fin_recv, cx, buf)
     }
 }
 
 impl AsyncWrite for BidiStream {
     fn poll_write(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        let message = Message::Data {
-            stream_id: self.id,
-            bytes: buf.to_vec(),
-            fin: false,
-        };
-        match self.tx.send(message) {
-            Ok(_) => Poll::Ready(Ok(buf.len())),
-            Err(err) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, err))),
-        }
+        let id = self.id;
+        poll_send_data(&mut self.tx, id, cx, buf)
     }
 
     fn poll_flush(
@@ -67,14 +33,12 @@ impl AsyncWrite for BidiStream {
     }
 
     fn poll_shutdown(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
+        ready!(self.tx.poll_reserve(cx))?;
         let message = Message::Close(self.id);
-        match self.tx.send(message) {
-            Ok(_) => Poll::Ready(Ok(())),
-            Err(err) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, err))),
-        }
+        Poll::Ready(self.tx.try_send(message))
     }
 }
 
@@ -84,55 +48,19 @@ impl AsyncRead for UniStream<Readable> {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        match self.rx.poll_recv(cx) {
-            Poll::Ready(Some(message)) => match message {
-                Ok(Message::Data {
-                       stream_id: _,
-                       bytes,
-                       fin,
-                   }) => {
-                    if fin {
-                        self.rx.close();
-                    }
-                    self.buffer.extend_from_slice(&bytes);
-                    let read_amount = buf.remaining_mut().min(self.buffer.len());
-                    buf.put_slice(&self.buffer[..read_amount]);
-                    buf.set_filled(read_amount);
-                    self.buffer.rotate_left(read_amount);
-                    let truncate_len = self.buffer.len() - read_amount;
-                    self.buffer.truncate(truncate_len);
-                    Poll::Ready(Ok(()))
-                }
-                Ok(Message::Close(_id)) => {
-                    self.rx.close();
-                    Poll::Ready(Ok(()))
-                },
-                Err(err) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err.to_string()))),
-            },
-            Poll::Ready(None) => Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "No new data is available to be read, stream is closed!",
-            ))),
-            Poll::Pending => Poll::Pending,
-        }
+        let this = &mut *self;
+        poll_read_buffered(&mut this.rx, &mut this.buffer, &mut this.fin_recv, cx, buf)
     }
 }
 
 impl AsyncWrite for UniStream<Writeable> {
     fn poll_write(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        let message = Message::Data {
-            stream_id: self.id,
-            bytes: buf.to_vec(),
-            fin: false,
-        };
-        match self.tx.send(message) {
-            Ok(_) => Poll::Ready(Ok(buf.len())),
-            Err(err) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, err))),
-        }
+        let id = self.id;
+        poll_send_data(&mut self.tx, id, cx, buf)
     }
 
     fn poll_flush(
@@ -143,13 +71,190 @@ impl AsyncWrite for UniStream<Writeable> {
     }
 
     fn poll_shutdown(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
+        ready!(self.tx.poll_reserve(cx))?;
         let message = Message::Close(self.id);
-        match self.tx.send(message) {
-            Ok(_) => Poll::Ready(Ok(())),
-            Err(err) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, err))),
+        Poll::Ready(self.tx.try_send(message))
+    }
+}
+
+/// Shared read path of all readable streams.
+///
+/// Data that is already buffered is always handed out first. Once the peer has sent a FIN and
+/// the buffer is drained, every read completes without filling `buf`, which signals EOF.
+/// Errors are only returned if the stream was reset or the connection went away before the FIN.
+fn poll_read_buffered(
+    rx: &mut Receiver<crate::error::Result<Message>>,
+    buffer: &mut BytesMut,
+    fin_recv: &mut bool,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<io::Result<()>> {
+    loop {
+        if !buffer.is_empty() || buf.remaining() == 0 {
+            let read_amount = buf.remaining().min(buffer.len());
+            buf.put_slice(&buffer[..read_amount]);
+            buffer.advance(read_amount);
+            return Poll::Ready(Ok(()));
+        }
+        if *fin_recv {
+            return Poll::Ready(Ok(()));
+        }
+        buffer_message(ready!(rx.poll_recv(cx)), rx, buffer, fin_recv)?;
+    }
+}
+
+/// Shared write path of all writable streams.
+///
+/// Waits for room in the connection's message queue and hands it at most `STREAM_BUFFER_SIZE`
+/// bytes, so that a single large write cannot grow the queue beyond its limit.
+fn poll_send_data(
+    tx: &mut StreamSender,
+    stream_id: u64,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+) -> Poll<io::Result<usize>> {
+    ready!(tx.poll_reserve(cx))?;
+    let len = buf.len().min(STREAM_BUFFER_SIZE);
+    tx.try_send(Message::Data {
+        stream_id,
+        bytes: buf[..len].to_vec(),
+        fin: false,
+    })?;
+    Poll::Ready(Ok(len))
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{Limits, Settings};
+    use crate::connection::Incoming;
+    use crate::test_util::{connection_pair, connection_pair_with, payload, settings};
+
+    #[tokio::test]
+    async fn read_to_end_bidi() {
+        let (mut client, mut server) = connection_pair().await;
+        let data = payload(200_000);
+
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        // Reads after EOF keep returning zero.
+        assert_eq!(incoming.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn read_to_end_uni() {
+        let (mut client, mut server) = connection_pair().await;
+        let data = payload(50_000);
+
+        let mut stream = client.uni(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming uni stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn read_exact_serves_leftover_bytes() {
+        let (mut client, mut server) = connection_pair().await;
+
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.write_all(b"0123456789").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        let mut head = [0; 4];
+        incoming.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"0123");
+        let mut tail = [0; 6];
+        incoming.read_exact(&mut tail).await.unwrap();
+        assert_eq!(&tail, b"456789");
+        let err = incoming.read_exact(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn copy_echo() {
+        let (mut client, mut server) = connection_pair().await;
+        let data = payload(100_000);
+
+        let echo = tokio::spawn(async move {
+            let Some(Incoming::Bidi(incoming)) = server.incoming().await else {
+                panic!("Expected an incoming bidi stream!");
+            };
+            let (mut reader, mut writer) = tokio::io::split(incoming);
+            let copied = tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            writer.shutdown().await.unwrap();
+            copied
+        });
+
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(echo.await.unwrap(), data.len() as u64);
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn slow_reader_with_small_limits() {
+        let limits = Limits {
+            connection_messages: 1,
+            pending_send_bytes: 16 * 1024,
+            incoming_streams: 1,
+            stream_messages: 1,
+            ..Default::default()
+        };
+        let (mut client, mut server) = connection_pair_with(Settings {
+            limits,
+            ..settings()
+        })
+        .await;
+        let data = payload(1_000_000);
+
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                let mut stream = client.uni(1).await.unwrap();
+                stream.write_all(&data).await.unwrap();
+                stream.shutdown().await.unwrap();
+                client
+            })
+        };
+
+        let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming uni stream!");
+        };
+        let mut received = Vec::new();
+        let mut buf = [0; 8192];
+        loop {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            match incoming.read(&mut buf).await.unwrap() {
+                0 => break,
+                read => received.extend_from_slice(&buf[..read]),
+            }
         }
+        assert_eq!(received, data);
+        writer.await.unwrap();
     }
 }
@@ -1,25 +1,31 @@
 use std::{
+    net::SocketAddr,
     sync::Arc,
     task::{ready, Poll},
 };
 
 use quiche::Connection;
-use tokio::{io::ReadBuf, net::UdpSocket};
 
-use crate::backend::{to_io_error, to_wire, IoHandler};
+use crate::backend::{
+    to_io_error, to_wire,
+    udp::{RecvBatch, SendBatch},
+    IoHandler,
+};
 
 use crate::error::Result;
+use crate::stats::EcnCounts;
+use crate::transport::DatagramTransport;
 
 use super::timer::Timer;
 
 pub(crate) struct Inner {
-    pub io: Arc<UdpSocket>,
+    pub io: Arc<dyn DatagramTransport>,
+    /// The server, datagrams from any other address are dropped.
+    pub peer: SocketAddr,
     pub connection: Connection,
-    pub send_flush: bool,
-    pub send_end: usize,
-    pub send_pos: usize,
-    pub recv_buf: Vec<u8>,
-    pub send_buf: Vec<u8>,
+    pub send_batch: SendBatch,
+    pub recv_batch: RecvBatch,
+    pub ecn: EcnCounts,
     pub timer: Timer,
 }
 
@@ -32,61 +38,47 @@ impl IoHandler for Inner {
         &mut self.connection
     }
 
-    fn poll_send(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
-        if self.send_flush {
-            while self.send_pos != self.send_end {
-                let n = ready!(self.io.poll_send(cx, &self.send_buf[self.send_pos..]))?;
-                self.send_pos += n;
-            }
-
-            self.send_pos = 0;
-            self.send_end = 0;
-            self.send_flush = false;
-        }
+    fn ecn_counts(&self) -> EcnCounts {
+        self.ecn
+    }
 
-        match self.connection.send(&mut self.send_buf[self.send_end..]) {
-            Ok((n, _info)) => {
-                self.send_end += n;
-                self.send_flush = self.send_end == self.send_buf.len();
-            }
-            Err(quiche::Error::Done) if self.send_pos != self.send_end => (),
-            Err(quiche::Error::Done) => return Poll::Pending,
-            Err(quiche::Error::BufferTooShort) => {
-                self.send_flush = true;
-                return Poll::Ready(Ok(()));
-            }
-            Err(err) => {
+    fn poll_send(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
+        if self.send_batch.is_empty() {
+            if let Err(err) = self.send_batch.fill(&mut self.connection) {
                 self.connection
                     .close(false, to_wire(err), b"fail")
                     .map_err(to_io_error)?;
+            }
+            if self.send_batch.is_empty() {
                 return Poll::Pending;
             }
         }
-
-        let n = ready!(self
-            .io
-            .poll_send(cx, &self.send_buf[self.send_pos..self.send_end]))?;
-        self.send_pos += n;
-
+        ready!(self.send_batch.poll_flush(&*self.io, cx))?;
         Poll::Ready(Ok(()))
     }
 
     fn poll_recv(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
-        let buf = &mut ReadBuf::new(&mut self.recv_buf);
-        let from = ready!(self.io.poll_recv_from(cx, buf))?;
-        let info = quiche::RecvInfo {
-            from,
-            to: self.io.local_addr()?,
-        };
-        match self.connection.recv(buf.filled_mut(), info) {
-            Ok(_) => Poll::Ready(Ok(())),
-            Err(quiche::Error::Done) => Poll::Ready(Ok(())),
-            Err(err) => {
-                self.connection
-                    .close(false, to_wire(err), b"fail")
-                    .map_err(to_io_error)?;
-                Poll::Pending
+        let count = ready!(self.recv_batch.poll_recv(&*self.io, cx))?;
+        let to = self.io.local_addr()?;
+        for (meta, datagram) in self.recv_batch.datagrams(count) {
+            if meta.addr != self.peer {
+                continue;
+            }
+            self.ecn.record(meta.ecn);
+            let info = quiche::RecvInfo {
+                from: meta.addr,
+                to,
+            };
+            match self.connection.recv(datagram, info) {
+                Ok(_) | Err(quiche::Error::Done) => {}
+                Err(err) => {
+                    self.connection
+                        .close(false, to_wire(err), b"fail")
+                        .map_err(to_io_error)?;
+                    return Poll::Pending;
+                }
             }
         }
+        Poll::Ready(Ok(()))
     }
 }
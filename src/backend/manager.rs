@@ -1,47 +1,78 @@
-use std::{collections::HashMap, future::Future, net::SocketAddr, sync::Arc, task::ready};
+use std::{
+    collections::HashMap, future::Future, io, net::SocketAddr, pin::Pin, sync::Arc, task::Poll,
+};
 
-use log::{error, warn};
 use ring::hmac::Key;
-use tokio::{
-    io::ReadBuf,
-    net::UdpSocket,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+use tokio::sync::{
+    mpsc::{self, error::TrySendError, Receiver, Sender},
+    watch,
 };
 
+use super::pool::{BufferPool, PooledBuf};
+use super::udp::{RecvBatch, RecvMeta};
 use crate::{
+    config::{SelectCongestionControl, Settings},
     crypto::{mint_token, validate_token},
     error::Result,
+    metrics::{Channel, DropReason, Metrics},
+    tls::Reload,
+    trace::{event, Dbg},
+    transport::DatagramTransport,
     MAX_DATAGRAM_SIZE,
 };
 
 pub struct Client {
     pub connection: quiche::Connection,
-    pub recv: UnboundedReceiver<DataPacket>,
+    pub recv: Receiver<DataPacket>,
+    pub from: SocketAddr,
 }
 
 pub struct DataPacket {
     pub from: SocketAddr,
-    pub data: Vec<u8>,
+    /// The ECN bits of the IP header.
+    pub ecn: u8,
+    pub data: PooledBuf,
 }
 
 /// The Manager is responsible for driving the backend operations.
 /// It collects and emits data from the channels and `QuicStream`s.
 pub struct Manager {
-    io: Arc<UdpSocket>,
-    client_map: HashMap<quiche::ConnectionId<'static>, UnboundedSender<DataPacket>>,
+    io: Arc<dyn DatagramTransport>,
+    client_map: HashMap<quiche::ConnectionId<'static>, Sender<DataPacket>>,
     seed: Key,
     secret_sauce: Vec<u8>,
     config: quiche::Config,
-    connection_send: UnboundedSender<Client>,
+    /// Resolves once `TlsReloader::reload` sent a replacement for `config`,
+    /// `None` once every reloader was dropped.
+    config_changed: Option<ConfigChanged>,
+    connection_send: Sender<Client>,
+    metrics: Arc<dyn Metrics>,
+    /// Capacity of the packet queue of every connection.
+    packet_capacity: usize,
+    select_congestion_control: Option<SelectCongestionControl>,
+    pool: BufferPool,
+    /// Datagrams are received into this batch, before each is copied into a buffer from the `pool`.
+    recv_batch: RecvBatch,
+    /// Holds version negotiation and retry packets.
+    send_buf: Vec<u8>,
+}
+
+/// Hands the receiver back once its value changed, `None` once the sender was dropped.
+type ConfigChanged = Pin<Box<dyn Future<Output = Option<watch::Receiver<Reload>>> + Send>>;
+
+fn config_changed(mut config_recv: watch::Receiver<Reload>) -> ConfigChanged {
+    Box::pin(async move { config_recv.changed().await.ok().map(|()| config_recv) })
 }
 
 impl Manager {
     pub fn new(
-        io: Arc<UdpSocket>,
+        io: Arc<dyn DatagramTransport>,
         seed: Key,
         secret_sauce: Vec<u8>,
         config: quiche::Config,
-        connection_send: UnboundedSender<Client>,
+        config_recv: watch::Receiver<Reload>,
+        connection_send: Sender<Client>,
+        settings: &Settings,
     ) -> Self {
         Self {
             io,
@@ -49,140 +80,276 @@ impl Manager {
             seed,
             secret_sauce,
             config,
+            config_changed: Some(config_changed(config_recv)),
             connection_send,
+            metrics: settings.metrics.clone(),
+            packet_capacity: settings.limits.connection_packets,
+            select_congestion_control: settings.select_congestion_control.clone(),
+            pool: BufferPool::default(),
+            recv_batch: RecvBatch::new(),
+            send_buf: vec![0; MAX_DATAGRAM_SIZE],
         }
     }
 }
 
-impl Future for Manager {
-    type Output = Result<()>;
-
-    fn poll(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        let mut buffer: Vec<u8> = vec![0; 65535];
-        let mut data_buf: Vec<u8> = vec![0; MAX_DATAGRAM_SIZE];
-        'driver: loop {
-            let buf = &mut ReadBuf::new(&mut buffer);
-            let from = ready!(self.io.poll_recv_from(cx, buf))?;
-
-            let hdr = match quiche::Header::from_slice(buf.filled_mut(), quiche::MAX_CONN_ID_LEN) {
-                Ok(header) => header,
-                Err(err) => {
-                    error!("Error while parsing packet header: {:?}", err);
-                    continue 'driver;
-                }
-            };
+impl Manager {
+    /// Routes a single datagram to its connection, or answers it if it starts a new one.
+    fn handle_datagram(&mut self, meta: &RecvMeta, datagram: &mut [u8]) -> Result<()> {
+        let from = meta.addr;
+        let hdr = match quiche::Header::from_slice(datagram, quiche::MAX_CONN_ID_LEN) {
+            Ok(header) => header,
+            Err(err) => {
+                event!(
+                    debug,
+                    "Dropped packet",
+                    reason = DropReason::InvalidHeader,
+                    from = from,
+                    error = err
+                );
+                self.metrics.packet_dropped(DropReason::InvalidHeader);
+                return Ok(());
+            }
+        };
 
+        // The HMAC is only needed for packets that do not belong to a known connection.
+        let conn_id: quiche::ConnectionId = if self.client_map.contains_key(&hdr.dcid) {
+            quiche::ConnectionId::default()
+        } else {
             let conn_id = ring::hmac::sign(&self.seed, &hdr.dcid);
-            let conn_id = &conn_id.as_ref()[..quiche::MAX_CONN_ID_LEN];
-            let conn_id: quiche::ConnectionId = conn_id.to_vec().into();
-
-            let sender = if !self.client_map.contains_key(&hdr.dcid)
-                && !self.client_map.contains_key(&conn_id)
-            {
-                if hdr.ty != quiche::Type::Initial {
-                    error!("Got Initial packet without having a known connection.");
-                    continue 'driver;
+            conn_id.as_ref()[..quiche::MAX_CONN_ID_LEN].to_vec().into()
+        };
+
+        let sender = if !self.client_map.contains_key(&hdr.dcid)
+            && !self.client_map.contains_key(&conn_id)
+        {
+            if hdr.ty != quiche::Type::Initial {
+                event!(
+                    debug,
+                    "Dropped packet",
+                    reason = DropReason::UnknownConnectionId,
+                    from = from,
+                    ty = Dbg(hdr.ty),
+                    dcid = Dbg(&hdr.dcid),
+                );
+                self.metrics.packet_dropped(DropReason::UnknownConnectionId);
+                return Ok(());
+            }
+            self.metrics.initial_received();
+
+            if !quiche::version_is_supported(hdr.version) {
+                event!(
+                    info,
+                    "Sending version negotiation",
+                    from = from,
+                    version = hdr.version,
+                );
+                let len =
+                    quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut self.send_buf).unwrap();
+                let data_buf = &self.send_buf[..len];
+
+                match send_stateless(&*self.io, data_buf, from) {
+                    Ok(()) => self.metrics.version_negotiation_sent(),
+                    Err(err) => event!(
+                        error,
+                        "Failed to send version negotiation",
+                        from = from,
+                        error = err
+                    ),
                 }
 
-                if !quiche::version_is_supported(hdr.version) {
-                    warn!(
-                        "Requested version ({}) not supported, starting to negotiate...",
-                        hdr.version
-                    );
-                    let len =
-                        quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut data_buf).unwrap();
-                    let data_buf = &data_buf[..len];
-
-                    if let Err(err) = ready!(self.io.poll_send_to(cx, data_buf, from)) {
-                        error!("Failed to send negotiation: {:?}", err);
-                    }
-
-                    continue 'driver;
-                }
+                return Ok(());
+            }
 
-                let mut scid = [0; quiche::MAX_CONN_ID_LEN];
-                scid.copy_from_slice(&conn_id);
+            let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+            scid.copy_from_slice(&conn_id);
 
-                let scid = quiche::ConnectionId::from_ref(&scid);
+            let scid = quiche::ConnectionId::from_ref(&scid);
 
-                let token = hdr.token.as_ref().unwrap();
+            let token = hdr.token.as_ref().unwrap();
 
-                // If empty mint new token
-                if token.is_empty() {
-                    let new_token = mint_token(&hdr.dcid, &from, &self.secret_sauce);
+            // If empty mint new token
+            if token.is_empty() {
+                let new_token = mint_token(&hdr.dcid, &from, &self.secret_sauce);
 
-                    let len = quiche::retry(
-                        &hdr.scid,
-                        &hdr.dcid,
-                        &scid,
-                        &new_token,
-                        hdr.version,
-                        &mut data_buf,
-                    )
-                    .unwrap();
+                let len = quiche::retry(
+                    &hdr.scid,
+                    &hdr.dcid,
+                    &scid,
+                    &new_token,
+                    hdr.version,
+                    &mut self.send_buf,
+                )
+                .unwrap();
 
-                    let data_buf = &data_buf[..len];
+                let data_buf = &self.send_buf[..len];
 
-                    if let Err(err) = ready!(self.io.poll_send_to(cx, data_buf, from)) {
-                        error!("Failed to send negotiation: {:?}", err);
+                event!(debug, "Sending retry", from = from, dcid = Dbg(&hdr.dcid));
+                match send_stateless(&*self.io, data_buf, from) {
+                    Ok(()) => self.metrics.retry_sent(),
+                    Err(err) => {
+                        event!(error, "Failed to send retry", from = from, error = err)
                     }
-
-                    continue 'driver;
                 }
 
-                let odcid = validate_token(token, &from, &self.secret_sauce, Some(180));
+                return Ok(());
+            }
 
-                if odcid.is_none() {
-                    error!("Invalid address validation token");
-                    continue 'driver;
-                }
+            let odcid = validate_token(token, &from, &self.secret_sauce, Some(180));
+
+            if odcid.is_none() {
+                event!(
+                    warn,
+                    "Dropped packet",
+                    reason = DropReason::InvalidToken,
+                    from = from
+                );
+                self.metrics.packet_dropped(DropReason::InvalidToken);
+                return Ok(());
+            }
 
-                if scid.len() != hdr.dcid.len() {
-                    error!("Invalid destination connection ID");
-                    continue 'driver;
-                }
+            if scid.len() != hdr.dcid.len() {
+                event!(
+                    warn,
+                    "Dropped packet",
+                    reason = DropReason::InvalidConnectionId,
+                    from = from
+                );
+                self.metrics.packet_dropped(DropReason::InvalidConnectionId);
+                return Ok(());
+            }
 
-                let scid = hdr.dcid.clone();
+            let scid = hdr.dcid.clone();
 
-                let conn = quiche::accept(
-                    &scid,
-                    odcid.as_ref(),
-                    self.io.local_addr()?,
-                    from,
-                    &mut self.config,
-                )
-                .unwrap();
+            if let Some(select) = &self.select_congestion_control {
+                select(from).apply(&mut self.config);
+            }
+            let conn = quiche::accept(
+                &scid,
+                odcid.as_ref(),
+                self.io.local_addr()?,
+                from,
+                &mut self.config,
+            )
+            .unwrap();
 
-                let (tx, rx) = mpsc::unbounded_channel();
+            let (tx, rx) = mpsc::channel(self.packet_capacity);
 
-                let client = Client {
-                    connection: conn,
-                    recv: rx,
-                };
+            let client = Client {
+                connection: conn,
+                recv: rx,
+                from,
+            };
 
-                if self.connection_send.send(client).is_err() {
-                    error!("Failed to send client to thread!");
-                    continue 'driver;
+            match self.connection_send.try_send(client) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    // The client will retransmit its Initial, maybe there is room by then.
+                    event!(
+                        warn,
+                        "Dropped packet",
+                        reason = DropReason::AcceptQueueFull,
+                        from = from
+                    );
+                    self.metrics.packet_dropped(DropReason::AcceptQueueFull);
+                    return Ok(());
                 }
+                Err(TrySendError::Closed(_)) => {
+                    event!(error, "Failed to hand over new connection", from = from);
+                    self.metrics.channel_send_failed(Channel::Accept);
+                    return Ok(());
+                }
+            }
+            self.metrics.connection_accepted();
+            event!(debug, "New connection", from = from, scid = Dbg(&scid));
 
-                self.client_map.insert(scid.clone(), tx);
+            self.client_map.insert(scid.clone(), tx);
 
-                self.client_map.get_mut(&scid).unwrap()
-            } else {
-                match self.client_map.get_mut(&hdr.dcid) {
-                    Some(v) => v,
-                    None => self.client_map.get_mut(&conn_id).unwrap(),
-                }
+            self.client_map.get_mut(&scid).unwrap()
+        } else {
+            match self.client_map.get_mut(&hdr.dcid) {
+                Some(v) => v,
+                None => self.client_map.get_mut(&conn_id).unwrap(),
+            }
+        };
+        let packet = DataPacket {
+            from,
+            ecn: meta.ecn,
+            data: self.pool.copy_from(datagram),
+        };
+        match sender.try_send(packet) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                event!(
+                    debug,
+                    "Dropped packet",
+                    reason = DropReason::ConnectionQueueFull,
+                    from = from
+                );
+                self.metrics.packet_dropped(DropReason::ConnectionQueueFull);
+            }
+            Err(TrySendError::Closed(_)) => {
+                event!(
+                    warn,
+                    "Dropped packet",
+                    reason = "connection is gone",
+                    from = from
+                );
+                self.metrics.channel_send_failed(Channel::Packet);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Future for Manager {
+    type Output = Result<()>;
+
+    /// Handles every datagram that is ready, until the socket would block.
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // Connections that were accepted already keep the config they were created with.
+        while let Some(changed) = this.config_changed.as_mut() {
+            let Poll::Ready(config_recv) = changed.as_mut().poll(cx) else {
+                break;
             };
-            if let Err(err) = sender.send(DataPacket {
-                from,
-                data: buf.filled_mut().to_vec(),
-            }) {
-                error!("Failed to send data to thread: {err}");
+            let Some(mut config_recv) = config_recv else {
+                this.config_changed = None;
+                break;
+            };
+            let reload = config_recv.borrow_and_update().clone();
+            // Taken already if the manager saw this value before.
+            let taken = reload.lock().unwrap().take();
+            if let Some((config, applied)) = taken {
+                this.config = config;
+                event!(info, "Reloaded config");
+                // The reloader may have stopped waiting.
+                let _ = applied.send(());
             }
+            this.config_changed = Some(config_changed(config_recv));
+        }
+        loop {
+            let count = match this.recv_batch.poll_recv(&*this.io, cx) {
+                Poll::Ready(result) => result?,
+                Poll::Pending => return Poll::Pending,
+            };
+            // The batch is moved out, so that its datagrams can be borrowed next to `this`.
+            let mut batch = std::mem::replace(&mut this.recv_batch, RecvBatch::empty());
+            let result = batch
+                .datagrams(count)
+                .try_for_each(|(meta, datagram)| this.handle_datagram(meta, datagram));
+            this.recv_batch = batch;
+            result?;
         }
     }
 }
+
+/// Sends a version negotiation or retry packet without waiting for the transport.
+///
+/// Waiting would hold up all other connections, if the packet is dropped instead,
+/// the client retransmits its Initial.
+fn send_stateless(io: &dyn DatagramTransport, data: &[u8], to: SocketAddr) -> io::Result<()> {
+    io.try_send_to(data, to)
+}
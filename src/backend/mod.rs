@@ -1,36 +1,441 @@
-use super::error::Result;
+use super::error::{Error, Result};
 use crate::backend::timer::Timer;
-use crate::config::STREAM_BUFFER_SIZE;
+use crate::config::{Limits, Role, Settings, STREAM_BUFFER_SIZE};
+use crate::metrics::{Channel, Metrics};
 
+use crate::stats::{self, ConnectionStats, EcnCounts, StreamCounters};
 use crate::stream::UncheckedQuicStream;
+use crate::trace::{self, event, span, Dbg};
 use crate::Message;
+use bytes::{Buf, BytesMut};
 use quiche::Connection;
 use std::collections::HashMap;
 use std::future::Future;
 use std::io;
+use std::net::SocketAddr;
 
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{ready, Poll};
-use std::time::Instant;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::sync::{mpsc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, Instant, Sleep};
 
 pub(crate) mod client;
 pub(crate) mod manager;
+pub(crate) mod pool;
 pub(crate) mod server;
 pub(crate) mod timer;
+pub(crate) mod udp;
+
+/// Urgency quiche assigns to streams that were not given a priority.
+const DEFAULT_URGENCY: u8 = 127;
+
+/// Stream data that was handed to the driver but not yet accepted by quiche.
+#[derive(Default)]
+pub(crate) struct PendingSend {
+    pub bytes: BytesMut,
+    pub fin: bool,
+}
+
+/// Resolves once a full channel that a stream's data is read into has room again.
+pub(crate) type BlockedRead = Pin<Box<dyn Future<Output = ()> + Send>>;
 
 pub(crate) struct Driver<Inner: IoHandler> {
     pub inner: Inner,
-    pub stream_map: Arc<Mutex<HashMap<u64, UnboundedSender<Result<Message>>>>>,
-    pub message_recv: UnboundedReceiver<Message>,
-    pub message_send: UnboundedSender<Message>,
-    pub incoming_send: UnboundedSender<UncheckedQuicStream>,
+    /// Channels to the streams, owned by the driver alone.
+    /// A channel is dropped once nobody reads its stream and quiche no longer knows the stream.
+    pub streams: HashMap<u64, Sender<Result<Message>>>,
+    /// Stream data, not read from while `Limits::pending_send_bytes` are pending.
+    pub message_recv: Receiver<Message>,
+    pub message_send: Sender<Message>,
+    /// All other messages, which are always handled right away.
+    pub control_recv: Receiver<Message>,
+    pub control_send: Sender<Message>,
+    pub incoming_send: Sender<UncheckedQuicStream>,
+    pub pending_send: HashMap<u64, PendingSend>,
+    /// Urgency of every stream that was given an explicit priority.
+    pub priorities: HashMap<u64, u8>,
+    pub stream_stats: HashMap<u64, StreamCounters>,
+    /// Streams that are not read from until their channel has room again.
+    pub blocked_reads: HashMap<u64, BlockedRead>,
+    pub metrics: Arc<dyn Metrics>,
+    pub limits: Limits,
+    pub keep_alive: Option<KeepAlive>,
+    /// Pings waiting for an acknowledgment.
+    pub pings: Vec<Ping>,
+}
+
+/// Sends an ack-eliciting packet at a fixed interval, see `Settings::keep_alive`.
+pub(crate) struct KeepAlive {
+    interval: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl KeepAlive {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            sleep: Box::pin(sleep(interval)),
+        }
+    }
+}
+
+/// A `QuicConnection::ping` that was not acknowledged yet.
+pub(crate) struct Ping {
+    reply: oneshot::Sender<Duration>,
+    /// `sent_bytes` of the connection when the ping was requested.
+    sent_before: u64,
+    /// `sent_bytes` of the connection once the ping was sent, and when that was.
+    sent: Option<(u64, Instant)>,
 }
 
 impl<Inner: IoHandler> Unpin for Driver<Inner> {}
 
+impl<Inner: IoHandler> Driver<Inner> {
+    /// Hands as much pending stream data to quiche as its flow control and congestion window
+    /// allow. Whatever is left over stays queued until the next iteration.
+    ///
+    /// Streams are served in order of their urgency (then by id), so that a stream with a
+    /// lower urgency gets the available capacity before any bulk data is queued up behind it.
+    fn flush_pending_send(&mut self) {
+        self.forget_priorities();
+        self.forget_streams();
+        let mut order: Vec<(u8, u64)> = self
+            .pending_send
+            .keys()
+            .map(|stream_id| {
+                let urgency = self
+                    .priorities
+                    .get(stream_id)
+                    .copied()
+                    .unwrap_or(DEFAULT_URGENCY);
+                (urgency, *stream_id)
+            })
+            .collect();
+        order.sort_unstable();
+
+        for (_, stream_id) in order {
+            let pending = self.pending_send.get_mut(&stream_id).unwrap();
+            let result =
+                self.inner
+                    .connection()
+                    .stream_send(stream_id, &pending.bytes, pending.fin);
+            let counters = self.stream_stats.entry(stream_id).or_default();
+            match result {
+                Ok(written) => {
+                    pending.bytes.advance(written);
+                    counters.stats.bytes_sent += written as u64;
+                    counters.set_blocked(!pending.bytes.is_empty());
+                    if pending.bytes.is_empty() {
+                        if pending.fin {
+                            self.priorities.remove(&stream_id);
+                        }
+                        self.pending_send.remove(&stream_id);
+                    }
+                }
+                Err(quiche::Error::Done) => counters.set_blocked(true),
+                Err(err) => {
+                    counters.set_blocked(false);
+                    self.pending_send.remove(&stream_id);
+                    self.priorities.remove(&stream_id);
+                    self.send_error(stream_id, err);
+                }
+            }
+        }
+    }
+
+    /// Forgets the priorities of streams that will not send anything anymore, because quiche has
+    /// collected them, the peer stopped them, or they were dropped with no data left to send.
+    fn forget_priorities(&mut self) {
+        let Driver {
+            inner,
+            streams,
+            pending_send,
+            priorities,
+            ..
+        } = self;
+        let connection = inner.connection();
+        priorities.retain(|stream_id, _| {
+            pending_send.contains_key(stream_id)
+                || (connection.stream_capacity(*stream_id).is_ok()
+                    && streams.get(stream_id).is_some_and(|tx| !tx.is_closed()))
+        });
+    }
+
+    /// Drops the channels of streams whose receiver is gone, once quiche has collected the stream
+    /// or never created it and nothing is left to send. Their ids can be opened again.
+    fn forget_streams(&mut self) {
+        let Driver {
+            inner,
+            streams,
+            pending_send,
+            ..
+        } = self;
+        let connection = inner.connection();
+        streams.retain(|stream_id, tx| {
+            !tx.is_closed()
+                || pending_send.contains_key(stream_id)
+                || !matches!(
+                    connection.stream_capacity(*stream_id),
+                    Err(quiche::Error::InvalidStreamState(_))
+                )
+        });
+    }
+
+    /// Takes a statistics snapshot, forgetting the counters of streams quiche has collected.
+    fn stats(&mut self) -> ConnectionStats {
+        let Driver {
+            inner,
+            pending_send,
+            stream_stats,
+            ..
+        } = self;
+        let ecn = inner.ecn_counts();
+        let connection = inner.connection();
+        stream_stats.retain(|stream_id, _| {
+            pending_send.contains_key(stream_id)
+                || !matches!(
+                    connection.stream_capacity(*stream_id),
+                    Err(quiche::Error::InvalidStreamState(_))
+                )
+        });
+        stats::snapshot(connection, stream_stats, ecn)
+    }
+
+    /// Forwards an error to the stream it belongs to.
+    fn send_error(&self, stream_id: u64, err: quiche::Error) {
+        let _span = span!(debug, "stream", stream_id = stream_id).entered();
+        event!(debug, "Stream error", error = err);
+        if let Some(tx) = self.streams.get(&stream_id) {
+            if tx.try_send(Err(err.into())).is_err() {
+                self.metrics.channel_send_failed(Channel::Stream);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Data {
+                stream_id,
+                bytes,
+                fin,
+            } => {
+                let pending = self.pending_send.entry(stream_id).or_default();
+                pending.bytes.extend_from_slice(&bytes);
+                pending.fin |= fin;
+            }
+            // Only finishes the stream, the channel is shared by all streams of the connection.
+            Message::Close(stream_id) => {
+                self.pending_send.entry(stream_id).or_default().fin = true;
+            }
+            Message::Open {
+                stream_id,
+                tx,
+                priority,
+                reply,
+            } => {
+                let result = if self.streams.contains_key(&stream_id) {
+                    Err(Error::IdAlreadyTaken(stream_id))
+                } else if let Some((urgency, incremental)) = priority {
+                    self.set_priority(stream_id, urgency, incremental)
+                        .map_err(Error::from)
+                } else {
+                    Ok(())
+                };
+                if result.is_ok() {
+                    self.streams.insert(stream_id, tx);
+                }
+                let _ = reply.send(result);
+            }
+            Message::Priority {
+                stream_id,
+                urgency,
+                incremental,
+            } => {
+                if let Err(err) = self.set_priority(stream_id, urgency, incremental) {
+                    self.send_error(stream_id, err);
+                }
+            }
+            Message::Stats(reply) => {
+                let _ = reply.send(self.stats());
+            }
+            Message::Ping(reply) => {
+                let connection = self.inner.connection();
+                if let Err(err) = connection.send_ack_eliciting() {
+                    event!(debug, "Failed to send ping", error = err);
+                }
+                self.pings.push(Ping {
+                    reply,
+                    sent_before: connection.stats().sent_bytes,
+                    sent: None,
+                });
+            }
+            Message::KeepAlive(interval) => self.keep_alive = interval.map(KeepAlive::new),
+            Message::StreamStats { stream_id, reply } => {
+                let stats = self
+                    .stream_stats
+                    .get(&stream_id)
+                    .map(StreamCounters::snapshot)
+                    .unwrap_or_default();
+                let _ = reply.send(stats);
+            }
+        }
+    }
+
+    /// Passes the priority on to quiche and remembers it for `flush_pending_send`.
+    fn set_priority(
+        &mut self,
+        stream_id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> quiche::Result<()> {
+        self.inner
+            .connection()
+            .stream_priority(stream_id, urgency, incremental)?;
+        self.priorities.insert(stream_id, urgency);
+        Ok(())
+    }
+
+    /// Moves the next chunk of a readable stream into its channel.
+    ///
+    /// If the stream's channel, or for a new stream the queue of incoming streams, is full,
+    /// the data is left in quiche and the stream is not read from until there is room again.
+    /// Meanwhile quiche's flow control keeps the peer from sending much more.
+    fn read_stream(&mut self, stream_id: u64, stream_buf: &mut [u8]) {
+        let tx = match self.streams.get(&stream_id) {
+            Some(tx) => tx.clone(),
+            None => {
+                let incoming_send = self.incoming_send.clone();
+                let permit = match incoming_send.try_reserve() {
+                    Ok(permit) => Some(permit),
+                    Err(TrySendError::Full(())) => {
+                        self.block_read(stream_id, incoming_send.clone());
+                        return;
+                    }
+                    Err(TrySendError::Closed(())) => {
+                        self.metrics.channel_send_failed(Channel::Incoming);
+                        None
+                    }
+                };
+                let _span = span!(debug, "stream", stream_id = stream_id).entered();
+                event!(debug, "Incoming stream");
+                let (tx, rx) = mpsc::channel(self.limits.stream_messages);
+                if let Some(permit) = permit {
+                    permit.send(UncheckedQuicStream {
+                        id: stream_id,
+                        rx,
+                        tx: self.message_send.clone(),
+                        control: self.control_send.clone(),
+                    });
+                }
+                self.streams.insert(stream_id, tx.clone());
+                tx
+            }
+        };
+
+        let permit = match tx.try_reserve() {
+            Ok(permit) => Some(permit),
+            Err(TrySendError::Full(())) => {
+                self.block_read(stream_id, tx.clone());
+                return;
+            }
+            // Nobody reads this stream anymore, keep draining it so it does not hold up the peer.
+            Err(TrySendError::Closed(())) => None,
+        };
+
+        let message = match self.inner.connection().stream_recv(stream_id, stream_buf) {
+            Ok((len, fin)) => {
+                let counters = self.stream_stats.entry(stream_id).or_default();
+                counters.stats.bytes_received += len as u64;
+                Ok(Message::Data {
+                    stream_id,
+                    bytes: stream_buf[..len].to_vec(),
+                    fin,
+                })
+            }
+            Err(quiche::Error::Done) => return,
+            Err(err) => {
+                let _span = span!(debug, "stream", stream_id = stream_id).entered();
+                event!(debug, "Stream error", error = err);
+                Err(err.into())
+            }
+        };
+        match permit {
+            Some(permit) => permit.send(message),
+            None => self.metrics.channel_send_failed(Channel::Stream),
+        }
+    }
+
+    /// Stops reading `stream_id` until `tx` has room for another message.
+    fn block_read<T: Send + 'static>(&mut self, stream_id: u64, tx: Sender<T>) {
+        event!(trace, "Paused reading stream", stream_id = stream_id);
+        self.blocked_reads.insert(
+            stream_id,
+            Box::pin(async move {
+                // Also resolves if the receiver is gone, the stream is drained then.
+                let _ = tx.reserve().await;
+            }),
+        );
+    }
+
+    /// Sends a keep-alive if its interval has passed.
+    fn poll_keep_alive(&mut self, cx: &mut Context<'_>) {
+        let Some(keep_alive) = &mut self.keep_alive else {
+            return;
+        };
+        if keep_alive.sleep.as_mut().poll(cx).is_pending() {
+            return;
+        }
+        keep_alive
+            .sleep
+            .as_mut()
+            .reset(Instant::now() + keep_alive.interval);
+        // Registers the new deadline.
+        let _ = keep_alive.sleep.as_mut().poll(cx);
+        event!(trace, "Sending keep-alive");
+        if let Err(err) = self.inner.connection().send_ack_eliciting() {
+            event!(debug, "Failed to send keep-alive", error = err);
+        }
+    }
+
+    /// Notes when pings were sent and answers those whose packets were acknowledged since.
+    ///
+    /// A ping counts as acknowledged once every byte sent up to it was acknowledged or declared
+    /// lost, so that acknowledgments of packets sent before it do not answer it.
+    fn update_pings(&mut self) {
+        if self.pings.is_empty() {
+            return;
+        }
+        let stats = self.inner.connection().stats();
+        let now = Instant::now();
+        for mut ping in std::mem::take(&mut self.pings) {
+            match ping.sent {
+                None if stats.sent_bytes > ping.sent_before => {
+                    ping.sent = Some((stats.sent_bytes, now));
+                }
+                Some((sent_bytes, at)) if stats.acked_bytes + stats.lost_bytes >= sent_bytes => {
+                    let _ = ping.reply.send(now - at);
+                    continue;
+                }
+                _ => {}
+            }
+            if !ping.reply.is_closed() {
+                self.pings.push(ping);
+            }
+        }
+    }
+
+    /// Bytes of stream data that were received from the streams but not accepted by quiche yet.
+    fn pending_send_bytes(&self) -> usize {
+        self.pending_send
+            .values()
+            .map(|pending| pending.bytes.len())
+            .sum()
+    }
+}
+
 impl<Inner: IoHandler> Future for Driver<Inner> {
     type Output = Result<()>;
 
@@ -38,74 +443,41 @@ impl<Inner: IoHandler> Future for Driver<Inner> {
         let mut stream_buf = vec![0; STREAM_BUFFER_SIZE];
         loop {
             // Write Connection
-            while let Ok(result) = self.message_recv.try_recv() {
-                let (stream_id, result) = match result {
-                    Message::Data {
-                        stream_id,
-                        bytes,
-                        fin,
-                    } => (
-                        stream_id,
-                        self.inner.connection().stream_send(stream_id, &bytes, fin),
-                    ),
-                    Message::Close(stream_id) => {
-                        self.message_recv.close();
-                        (
-                            stream_id,
-                            self.inner.connection().stream_send(stream_id, &[], true),
-                        )
-                    },
+            while let Poll::Ready(Some(message)) = self.control_recv.poll_recv(cx) {
+                self.handle_message(message);
+            }
+            // Writers wait in front of the bounded channel while too much data is pending.
+            let mut pending_bytes = self.pending_send_bytes();
+            while pending_bytes < self.limits.pending_send_bytes {
+                let Poll::Ready(Some(message)) = self.message_recv.poll_recv(cx) else {
+                    break;
                 };
-                if let Err(err) = result {
-                    let mut map = pollster::block_on(self.stream_map.lock());
-                    if let Some(tx) = map.get_mut(&stream_id) {
-                        let _ = tx.send(Err(err.into()));
-                    }
+                if let Message::Data { bytes, .. } = &message {
+                    pending_bytes += bytes.len();
                 }
+                self.handle_message(message);
             }
+            self.flush_pending_send();
 
             // Read Connection
+            self.blocked_reads
+                .retain(|_, blocked| blocked.as_mut().poll(cx).is_pending());
             for stream_id in self.inner.connection().readable() {
-                if self.inner.connection().stream_finished(stream_id) {
-                    continue;
-                }
-                let incoming_send = self.incoming_send.clone();
-                let map = self.stream_map.clone();
-                let mut map = pollster::block_on(map.lock());
-
-                let message_send = self.message_send.clone();
-                let tx = map.entry(stream_id).or_insert_with(move || {
-                    let (tx, rx) = mpsc::unbounded_channel();
-                    incoming_send
-                        .send(UncheckedQuicStream {
-                            id: stream_id,
-                            rx,
-                            tx: message_send,
-                        })
-                        .unwrap();
-                    tx
-                });
-
-                match self
-                    .inner
-                    .connection()
-                    .stream_recv(stream_id, &mut stream_buf)
+                if self.inner.connection().stream_finished(stream_id)
+                    || self.blocked_reads.contains_key(&stream_id)
                 {
-                    Ok((len, fin)) => {
-                        let _ = tx.send(Ok(Message::Data {
-                            stream_id,
-                            bytes: stream_buf[..len].to_vec(),
-                            fin,
-                        }));
-                    }
-                    Err(err) => {
-                        let _ = tx.send(Err(err.into()));
-                    }
+                    continue;
                 }
+                self.read_stream(stream_id, &mut stream_buf);
             }
+            self.poll_keep_alive(cx);
+            self.update_pings();
             // IO
             if let Ok(opt) = ready!(self.inner.poll_io_complete(cx)) {
                 if opt.is_none() {
+                    log_close(self.inner.connection());
+                    let timed_out = self.inner.connection().is_timed_out();
+                    self.metrics.connection_closed(timed_out);
                     return Poll::Ready(Ok(()));
                 }
             }
@@ -121,10 +493,19 @@ impl<'a, H: IoHandler> Future for Handshaker<'a, H> {
         while !self.0.connection().is_established() {
             if let Ok(opt) = ready!(self.0.poll_io_complete(cx)) {
                 if opt.is_none() && !self.0.connection().is_established() {
+                    log_close(self.0.connection());
                     return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
                 }
             }
         }
+        let connection = self.0.connection();
+        event!(
+            info,
+            "Handshake complete",
+            alpn = String::from_utf8_lossy(connection.application_proto()),
+            server_name = connection.server_name().unwrap_or("-"),
+            resumed = connection.is_resumed(),
+        );
         Poll::Ready(Ok(()))
     }
 }
@@ -134,23 +515,28 @@ pub(crate) trait IoHandler {
 
     fn connection(&mut self) -> &mut Connection;
 
+    /// ECN codepoints of the datagrams received so far.
+    fn ecn_counts(&self) -> EcnCounts;
+
     fn poll_io_complete(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<Option<()>>> {
-        if self.timer().ready() {
+        if self.timer().poll_expired(cx) {
             self.connection().on_timeout();
         }
 
-        if let Some(timeout) = self.connection().timeout() {
-            *self.timer() = Timer::Set(Instant::now() + timeout)
-        } else {
-            *self.timer() = Timer::Unset;
-        }
+        let deadline = self
+            .connection()
+            .timeout()
+            .map(|timeout| Instant::now() + timeout);
+        self.timer().set(deadline);
 
         let recv_result = self.poll_recv(cx)?;
         let send_result = self.poll_send(cx)?;
+        // Registers the new deadline, or runs `on_timeout` in the next round if it already passed.
+        let timer_expired = self.timer().poll_expired(cx);
 
         match (self.connection().is_closed(), recv_result, send_result) {
             (true, ..) => Poll::Ready(Ok(None)),
-            (false, Poll::Pending, Poll::Pending) => Poll::Pending,
+            (false, Poll::Pending, Poll::Pending) if !timer_expired => Poll::Pending,
             (..) => Poll::Ready(Ok(Some(()))),
         }
     }
@@ -160,6 +546,71 @@ pub(crate) trait IoHandler {
     fn poll_recv(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>>;
 }
 
+/// Creates the span all events of a connection are recorded in.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn connection_span(
+    connection: &Connection,
+    peer: SocketAddr,
+    role: Role,
+    parent: &trace::Span,
+) -> trace::Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!(
+        parent: parent,
+        "connection",
+        trace_id = %connection.trace_id(),
+        peer = %peer,
+        role = %role,
+    );
+    #[cfg(not(feature = "tracing"))]
+    return trace::Span;
+}
+
+/// Applies the per-connection parts of the `Settings` to a freshly created connection.
+#[cfg_attr(not(feature = "qlog"), allow(unused_variables))]
+pub(crate) fn setup_connection(connection: &mut Connection, settings: &Settings, role: Role) {
+    if let Some(keylog) = &settings.keylog {
+        match keylog.writer() {
+            Ok(Some(writer)) => connection.set_keylog(writer),
+            Ok(None) => {}
+            Err(err) => event!(
+                error,
+                "Failed to open keylog",
+                trace_id = connection.trace_id(),
+                error = err
+            ),
+        }
+    }
+    #[cfg(feature = "qlog")]
+    if let Some(qlog) = &settings.qlog {
+        let trace_id = connection.trace_id().to_string();
+        match qlog.writer(&trace_id, role) {
+            Ok(writer) => connection.set_qlog(
+                writer,
+                format!("tokio-quicker {role}"),
+                format!("{role} connection {trace_id}"),
+            ),
+            Err(err) => event!(
+                error,
+                "Failed to create qlog writer",
+                trace_id = trace_id,
+                error = err
+            ),
+        }
+    }
+}
+
+/// Records why a connection was closed.
+fn log_close(connection: &Connection) {
+    event!(
+        info,
+        "Connection closed",
+        timed_out = connection.is_timed_out(),
+        local_error = Dbg(connection.local_error()),
+        peer_error = Dbg(connection.peer_error()),
+    );
+}
+
 pub(crate) fn to_wire(err: quiche::Error) -> u64 {
     match err {
         quiche::Error::Done => 0x0,
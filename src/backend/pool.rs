@@ -0,0 +1,79 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Number of idle buffers kept for reuse, any further ones are freed.
+const MAX_IDLE_BUFFERS: usize = 1024;
+
+/// Recycles the buffers inbound packets are handed to the connections in,
+/// so that receiving does not allocate once the listener is warmed up.
+#[derive(Clone, Default)]
+pub(crate) struct BufferPool {
+    idle: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    /// Copies `data` into a buffer from the pool, which returns to the pool once dropped.
+    pub fn copy_from(&self, data: &[u8]) -> PooledBuf {
+        let mut buf = self.idle.lock().unwrap().pop().unwrap_or_default();
+        buf.extend_from_slice(data);
+        PooledBuf {
+            buf,
+            idle: self.idle.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/// A packet buffer that is borrowed from a `BufferPool`.
+pub(crate) struct PooledBuf {
+    buf: Vec<u8>,
+    idle: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_BUFFERS {
+            idle.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferPool;
+
+    #[test]
+    fn reuses_buffers() {
+        let pool = BufferPool::default();
+        let first = pool.copy_from(&[1; 1200]);
+        let ptr = first.as_ptr();
+        drop(first);
+        assert_eq!(pool.idle(), 1);
+
+        let second = pool.copy_from(&[2; 800]);
+        assert_eq!(second.as_ptr(), ptr);
+        assert_eq!(&second[..], &[2; 800][..]);
+        assert_eq!(pool.idle(), 0);
+    }
+}
@@ -1,30 +1,26 @@
 use std::{
-    net::SocketAddr,
     sync::Arc,
     task::{ready, Poll},
 };
 
-use log::error;
 use quiche::Connection;
-use tokio::{net::UdpSocket, sync::mpsc::UnboundedReceiver};
+use tokio::sync::mpsc::Receiver;
 
-use super::{manager::DataPacket, timer::Timer};
+use super::{manager::DataPacket, timer::Timer, udp::SendBatch};
 use crate::backend::{to_io_error, to_wire, IoHandler};
 use crate::error::Result;
+use crate::stats::EcnCounts;
+use crate::trace::event;
+use crate::transport::DatagramTransport;
 
-#[allow(dead_code)]
 pub(crate) struct Inner {
-    pub io: Arc<UdpSocket>,
+    pub io: Arc<dyn DatagramTransport>,
     pub connection: Connection,
-    pub data_recv: UnboundedReceiver<DataPacket>,
-    pub send_flush: bool,
-    pub send_end: usize,
-    pub send_pos: usize,
-    #[allow(dead_code)]
-    pub recv_buf: Vec<u8>,
-    pub send_buf: Vec<u8>,
+    pub data_recv: Receiver<DataPacket>,
+    /// Sends over the listener's transport, every packet goes to the address quiche chose for it.
+    pub send_batch: SendBatch,
+    pub ecn: EcnCounts,
     pub timer: Timer,
-    pub last_address: Option<SocketAddr>,
 }
 
 impl IoHandler for Inner {
@@ -36,63 +32,37 @@ impl IoHandler for Inner {
         &mut self.connection
     }
 
-    fn poll_send(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
-        if self.last_address.is_none() {
-            return Poll::Pending;
-        }
-
-        if self.send_flush {
-            while self.send_pos != self.send_end {
-                let n = ready!(self.io.poll_send_to(
-                    cx,
-                    &self.send_buf[self.send_pos..],
-                    self.last_address.unwrap()
-                ))?;
-                self.send_pos += n;
-            }
-
-            self.send_pos = 0;
-            self.send_end = 0;
-            self.send_flush = false;
-        }
+    fn ecn_counts(&self) -> EcnCounts {
+        self.ecn
+    }
 
-        match self.connection.send(&mut self.send_buf[self.send_end..]) {
-            Ok((n, _info)) => {
-                self.send_end += n;
-                self.send_flush = self.send_end == self.send_buf.len();
-            }
-            Err(quiche::Error::Done) if self.send_pos != self.send_end => (),
-            Err(quiche::Error::Done) => return Poll::Pending,
-            Err(quiche::Error::BufferTooShort) => {
-                self.send_flush = true;
-                return Poll::Ready(Ok(()));
-            }
-            Err(err) => {
-                error!("Closing connection: {:?}", err);
+    fn poll_send(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
+        if self.send_batch.is_empty() {
+            if let Err(err) = self.send_batch.fill(&mut self.connection) {
+                event!(error, "Closing connection after send failure", error = err);
                 self.connection
                     .close(false, to_wire(err), b"fail")
                     .map_err(to_io_error)?;
+            }
+            if self.send_batch.is_empty() {
                 return Poll::Pending;
             }
         }
-
-        let n = ready!(self.io.poll_send_to(
-            cx,
-            &self.send_buf[self.send_pos..self.send_end],
-            self.last_address.unwrap()
-        ))?;
-        self.send_pos += n;
-
+        ready!(self.send_batch.poll_flush(&*self.io, cx))?;
         Poll::Ready(Ok(()))
     }
 
     fn poll_recv(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
-        let DataPacket { from, mut data } = ready!(self.data_recv.poll_recv(cx)).unwrap();
+        let DataPacket {
+            from,
+            ecn,
+            mut data,
+        } = ready!(self.data_recv.poll_recv(cx)).unwrap();
+        self.ecn.record(ecn);
         let info = quiche::RecvInfo {
             from,
             to: self.io.local_addr()?,
         };
-        self.last_address = Some(from);
         match self.connection.recv(&mut data, info) {
             Ok(_) => Poll::Ready(Ok(())),
             Err(quiche::Error::Done) => Poll::Ready(Ok(())),
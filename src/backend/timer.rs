@@ -1,16 +1,36 @@
-use std::time::Instant;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
 
-pub enum Timer {
-    Set(Instant),
-    Unset,
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Wakes the connection's task when quiche's timeout expires.
+///
+/// Built on `tokio::time`, so it follows a paused clock, e.g. in the `sim` module.
+pub struct Timer {
+    deadline: Option<Instant>,
+    sleep: Pin<Box<Sleep>>,
 }
 
 impl Timer {
-    pub fn ready(&mut self) -> bool {
-        let mut instant = Instant::now();
-        match self {
-            Timer::Set(time) => time <= &mut instant,
-            Timer::Unset => false,
+    /// Has to be called from within a tokio runtime.
+    pub fn new() -> Self {
+        Self {
+            deadline: None,
+            sleep: Box::pin(sleep_until(Instant::now())),
+        }
+    }
+
+    /// Moves the deadline, `None` disarms the timer.
+    pub fn set(&mut self, deadline: Option<Instant>) {
+        if let Some(deadline) = deadline.filter(|&deadline| Some(deadline) != self.deadline) {
+            self.sleep.as_mut().reset(deadline);
         }
+        self.deadline = deadline;
+    }
+
+    /// Whether the deadline has passed, otherwise the task is woken once it does.
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        self.deadline.is_some() && self.sleep.as_mut().poll(cx).is_ready()
     }
 }
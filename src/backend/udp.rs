@@ -0,0 +1,914 @@
+//! Batched datagram I/O.
+//!
+//! Any `DatagramTransport` sends and receives one datagram at a time. For a UDP socket with the
+//! `udp-offload` feature on Linux, the datagrams of a batch are handed to the kernel
+//! with a single `sendmmsg` call, where runs of equally sized datagrams to the same peer are
+//! passed as one GSO (`UDP_SEGMENT`) buffer. Receiving uses `recvmmsg` with GRO (`UDP_GRO`)
+//! enabled, so one call can return many coalesced datagrams.
+//! If the kernel lacks support for any of this, or without the feature, every datagram takes
+//! its own syscall.
+//! ECN marking and reporting (`IP_TOS`/`IPV6_TCLASS`) is only available on this path, too.
+//!
+//! With `Pacing` enabled a batch is only sent up to the first datagram whose release time lies
+//! in the future. That one is either handed to the kernel with its release time (`SO_TXTIME`),
+//! or the batch waits for a timer before sending it.
+
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use quiche::Connection;
+use smallvec::SmallVec;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::time::Sleep;
+
+use crate::config::{Pacing, Settings, SocketOptions, MAX_DATAGRAM_SIZE};
+use crate::trace::event;
+use crate::transport::DatagramTransport;
+
+/// Largest UDP payload that can be received.
+pub(crate) const MAX_UDP_PAYLOAD: usize = 65535;
+
+/// Number of datagrams quiche writes into a `SendBatch` before it is flushed.
+const SEND_BATCH_SIZE: usize = 32;
+
+/// Number of buffers in a `RecvBatch`, each one can hold many datagrams if GRO is used.
+const RECV_BATCH_SIZE: usize = if cfg!(all(feature = "udp-offload", target_os = "linux")) {
+    8
+} else {
+    1
+};
+
+/// The kernel refuses GSO buffers with more segments than this.
+#[cfg(all(feature = "udp-offload", target_os = "linux"))]
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// The payload of a GSO buffer has to fit into a single UDP datagram.
+const MAX_GSO_BYTES: usize = 64_000;
+
+/// Datagrams released this close to now are sent right away, waiting for them is not worth a timer.
+const PACING_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// One `sendmsg` worth of data.
+pub(crate) struct Transmit<'a> {
+    pub to: SocketAddr,
+    pub contents: &'a [u8],
+    /// Size of the GSO segments if `contents` holds more than one datagram.
+    pub segment_size: Option<usize>,
+    /// Release time for the kernel, only set if `Pacing::Kernel` is in effect.
+    pub txtime: Option<Instant>,
+}
+
+/// Where a buffer of a `RecvBatch` came from and how to split it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecvMeta {
+    pub addr: SocketAddr,
+    pub len: usize,
+    /// Size of the coalesced datagrams, equal to `len` if GRO was not used.
+    pub stride: usize,
+    /// The ECN bits of the IP header, `0` if they are not available.
+    pub ecn: u8,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            addr: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            len: 0,
+            stride: 0,
+            ecn: 0,
+        }
+    }
+}
+
+/// Prepares a transport for batched receiving and the requested pacing and ECN.
+///
+/// Whatever the transport does not support is turned off in `settings`,
+/// so they describe what is actually in effect afterwards.
+pub(crate) fn configure(io: &dyn DatagramTransport, settings: &mut Settings) {
+    #[cfg(all(feature = "udp-offload", target_os = "linux"))]
+    if let Some(io) = io.udp_socket() {
+        if let Err(err) = linux::enable_gro(io) {
+            event!(debug, "GRO is not available", error = err);
+        }
+        if settings.pacing == Some(Pacing::Kernel) {
+            if let Err(err) = linux::enable_txtime(io) {
+                event!(debug, "SO_TXTIME is not available", error = err);
+                settings.pacing = Some(Pacing::Timer);
+            }
+        }
+        if settings.ecn {
+            if let Err(err) = linux::enable_ecn(io) {
+                event!(debug, "ECN is not available", error = err);
+                settings.ecn = false;
+            }
+        }
+        return;
+    }
+    let _ = io;
+    if settings.pacing == Some(Pacing::Kernel) {
+        settings.pacing = Some(Pacing::Timer);
+    }
+    settings.ecn = false;
+}
+
+/// Binds a socket with `options` applied to the first address `addr` resolves to that works.
+pub(crate) async fn bind<A: ToSocketAddrs>(
+    addr: A,
+    options: &SocketOptions,
+) -> io::Result<UdpSocket> {
+    let mut last_err = None;
+    for addr in lookup_host(addr).await? {
+        match bind_addr(addr, options) {
+            Ok(io) => return Ok(io),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Could not resolve to any address",
+        )
+    }))
+}
+
+fn bind_addr(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if options.reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    if options.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(only_v6) = options.only_v6.filter(|_| addr.is_ipv6()) {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(dscp) = options.dscp {
+        let tos = u32::from(dscp) << 2;
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+            #[cfg(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux",
+                target_os = "macos",
+            ))]
+            SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+            #[cfg(not(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux",
+                target_os = "macos",
+            )))]
+            SocketAddr::V6(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "IPV6_TCLASS is not supported on this platform",
+                ))
+            }
+        }
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Packets of a connection waiting to be sent.
+pub(crate) struct SendBatch {
+    buf: Vec<u8>,
+    datagrams: Vec<Datagram>,
+    /// Number of `datagrams` that were already sent.
+    sent: usize,
+    /// Largest number of datagrams in one GSO buffer, `1` if GSO is unavailable.
+    gso_segments: usize,
+    pacing: Pacing,
+    /// Fires once the next datagram may be sent, with `Pacing::Timer`.
+    release: Option<Pin<Box<Sleep>>>,
+}
+
+struct Datagram {
+    start: usize,
+    len: usize,
+    to: SocketAddr,
+    /// Release time assigned by quiche's pacer.
+    at: Instant,
+}
+
+impl SendBatch {
+    /// `pacing` has to be the mode `configure` left in effect for the transport.
+    pub fn new(io: &dyn DatagramTransport, pacing: Pacing) -> Self {
+        Self {
+            buf: vec![0; SEND_BATCH_SIZE * MAX_DATAGRAM_SIZE],
+            datagrams: Vec::with_capacity(SEND_BATCH_SIZE),
+            sent: 0,
+            gso_segments: max_gso_segments(io),
+            pacing,
+            release: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Lets quiche write packets into the batch, until it has nothing left to send, the
+    /// batch is full or a packet has to wait for its release time.
+    ///
+    /// Every packet gets room for the connection's current `max_send_udp_payload_size`,
+    /// which grows as path MTU discovery succeeds.
+    pub fn fill(&mut self, connection: &mut Connection) -> quiche::Result<()> {
+        let now = Instant::now();
+        let size = connection.max_send_udp_payload_size();
+        if self.buf.len() < SEND_BATCH_SIZE * size {
+            self.buf.resize(SEND_BATCH_SIZE * size, 0);
+        }
+        let mut end = self
+            .datagrams
+            .last()
+            .map_or(0, |datagram| datagram.start + datagram.len);
+        while self.datagrams.len() < SEND_BATCH_SIZE && end + size <= self.buf.len() {
+            match connection.send(&mut self.buf[end..end + size]) {
+                Ok((len, info)) => {
+                    self.datagrams.push(Datagram {
+                        start: end,
+                        len,
+                        to: info.to,
+                        at: info.at,
+                    });
+                    end += len;
+                    // Every later packet is released later, too.
+                    if self.is_paced(info.at, now) {
+                        break;
+                    }
+                }
+                Err(quiche::Error::Done) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends everything in the batch, afterwards it is empty again.
+    pub fn poll_flush(
+        &mut self,
+        io: &dyn DatagramTransport,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.sent < self.datagrams.len() {
+            if let Some(release) = &mut self.release {
+                ready!(release.as_mut().poll(cx));
+                self.release = None;
+            }
+            let result = {
+                let transmits = self.transmits(Instant::now());
+                if transmits.is_empty() {
+                    None
+                } else {
+                    Some(
+                        ready!(poll_send(io, cx, &transmits))
+                            .map(|count| segments(&transmits[..count])),
+                    )
+                }
+            };
+            match result {
+                // The next datagram is held back by the pacer.
+                None => {
+                    let at = self.datagrams[self.sent].at;
+                    self.release = Some(Box::pin(tokio::time::sleep_until(at.into())));
+                }
+                Some(Ok(sent)) => self.sent += sent,
+                // Some devices cannot segment, the datagrams are sent one by one from now on.
+                Some(Err(err)) if self.gso_segments > 1 && is_gso_error(&err) => {
+                    event!(debug, "Disabled GSO after send failure", error = err);
+                    self.gso_segments = 1;
+                }
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+            }
+        }
+        self.datagrams.clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Groups the unsent datagrams into runs that can be sent as one GSO buffer each.
+    ///
+    /// All datagrams of a run go to the same peer and have the same size, except for the last
+    /// one, which may be shorter. A datagram that is paced ends the batch, it is sent on its own
+    /// with `Pacing::Kernel` and not at all with `Pacing::Timer`.
+    fn transmits(&self, now: Instant) -> SmallVec<[Transmit<'_>; SEND_BATCH_SIZE]> {
+        let mut transmits = SmallVec::new();
+        let mut first = self.sent;
+        while first < self.datagrams.len() {
+            let head = &self.datagrams[first];
+            let paced = self.is_paced(head.at, now);
+            if paced && self.pacing == Pacing::Timer {
+                break;
+            }
+            let mut end = first + 1;
+            while !paced
+                && end < self.datagrams.len()
+                && end - first < self.gso_segments
+                && (end - first + 1) * head.len <= MAX_GSO_BYTES
+                && self.datagrams[end - 1].len == head.len
+                && self.datagrams[end].len <= head.len
+                && self.datagrams[end].to == head.to
+                && !self.is_paced(self.datagrams[end].at, now)
+            {
+                end += 1;
+            }
+            let last = &self.datagrams[end - 1];
+            transmits.push(Transmit {
+                to: head.to,
+                contents: &self.buf[head.start..last.start + last.len],
+                segment_size: (end - first > 1).then_some(head.len),
+                txtime: paced.then_some(head.at),
+            });
+            first = end;
+        }
+        transmits
+    }
+
+    /// Whether a datagram released at `at` has to wait.
+    fn is_paced(&self, at: Instant, now: Instant) -> bool {
+        self.pacing != Pacing::Disabled && at > now + PACING_GRANULARITY
+    }
+}
+
+/// Number of datagrams in `transmits`.
+fn segments(transmits: &[Transmit]) -> usize {
+    transmits
+        .iter()
+        .map(|transmit| match transmit.segment_size {
+            Some(size) => transmit.contents.len().div_ceil(size),
+            None => 1,
+        })
+        .sum()
+}
+
+/// Buffers datagrams are received into.
+pub(crate) struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    meta: Vec<RecvMeta>,
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        Self {
+            bufs: (0..RECV_BATCH_SIZE)
+                .map(|_| vec![0; MAX_UDP_PAYLOAD])
+                .collect(),
+            meta: vec![RecvMeta::default(); RECV_BATCH_SIZE],
+        }
+    }
+
+    /// A batch without buffers, to take the place of one that is in use.
+    pub fn empty() -> Self {
+        Self {
+            bufs: Vec::new(),
+            meta: Vec::new(),
+        }
+    }
+
+    /// Receives as many datagrams as are ready and fit into the batch,
+    /// returns the number of buffers that were filled.
+    pub fn poll_recv(
+        &mut self,
+        io: &dyn DatagramTransport,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        poll_recv(io, cx, &mut self.bufs, &mut self.meta)
+    }
+
+    /// Iterates over the datagrams in the first `count` buffers,
+    /// splitting the ones that were coalesced by GRO.
+    pub fn datagrams(&mut self, count: usize) -> impl Iterator<Item = (&RecvMeta, &mut [u8])> {
+        self.bufs
+            .iter_mut()
+            .zip(&self.meta)
+            .take(count)
+            .flat_map(|(buf, meta)| {
+                buf[..meta.len]
+                    .chunks_mut(meta.stride.max(1))
+                    .map(move |datagram| (meta, datagram))
+            })
+    }
+}
+
+/// Sends a prefix of `transmits`, returns how many of them were sent.
+fn poll_send(
+    io: &dyn DatagramTransport,
+    cx: &mut Context<'_>,
+    transmits: &[Transmit],
+) -> Poll<io::Result<usize>> {
+    #[cfg(all(feature = "udp-offload", target_os = "linux"))]
+    if let Some(io) = io.udp_socket() {
+        loop {
+            ready!(io.poll_send_ready(cx))?;
+            match io.try_io(tokio::io::Interest::WRITABLE, || linux::send(io, transmits)) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+    let transmit = &transmits[0];
+    debug_assert!(transmit.segment_size.is_none() && transmit.txtime.is_none());
+    ready!(io.poll_send_to(cx, transmit.contents, transmit.to))?;
+    Poll::Ready(Ok(1))
+}
+
+/// Receives into a prefix of `bufs`, returns how many of them were filled.
+fn poll_recv(
+    io: &dyn DatagramTransport,
+    cx: &mut Context<'_>,
+    bufs: &mut [Vec<u8>],
+    meta: &mut [RecvMeta],
+) -> Poll<io::Result<usize>> {
+    #[cfg(all(feature = "udp-offload", target_os = "linux"))]
+    if let Some(io) = io.udp_socket() {
+        loop {
+            ready!(io.poll_recv_ready(cx))?;
+            match io.try_io(tokio::io::Interest::READABLE, || {
+                linux::recv(io, bufs, meta)
+            }) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+    let mut buf = tokio::io::ReadBuf::new(&mut bufs[0]);
+    let addr = ready!(io.poll_recv_from(cx, &mut buf))?;
+    let len = buf.filled().len();
+    meta[0] = RecvMeta {
+        addr,
+        len,
+        stride: len,
+        ecn: 0,
+    };
+    Poll::Ready(Ok(1))
+}
+
+/// GSO is available on kernels that know the `UDP_SEGMENT` socket option.
+#[cfg(all(feature = "udp-offload", target_os = "linux"))]
+fn max_gso_segments(io: &dyn DatagramTransport) -> usize {
+    match io.udp_socket() {
+        Some(io) if linux::gso_supported(io) => MAX_GSO_SEGMENTS,
+        _ => 1,
+    }
+}
+
+#[cfg(not(all(feature = "udp-offload", target_os = "linux")))]
+fn max_gso_segments(_io: &dyn DatagramTransport) -> usize {
+    1
+}
+
+/// Whether a send failed, because the network device cannot do segmentation offload.
+#[cfg(all(feature = "udp-offload", target_os = "linux"))]
+fn is_gso_error(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EIO) | Some(libc::EINVAL))
+}
+
+#[cfg(not(all(feature = "udp-offload", target_os = "linux")))]
+fn is_gso_error(_err: &io::Error) -> bool {
+    false
+}
+
+#[cfg(all(feature = "udp-offload", target_os = "linux"))]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+    use std::time::Instant;
+
+    use tokio::net::UdpSocket;
+
+    use super::{RecvMeta, Transmit, RECV_BATCH_SIZE, SEND_BATCH_SIZE};
+
+    /// Room for the control messages of a single datagram.
+    type Control = [u64; 8];
+
+    /// Not exported by `libc` for every architecture, this is the value of the common ones.
+    const SO_TXTIME: libc::c_int = 61;
+    const SCM_TXTIME: libc::c_int = SO_TXTIME;
+
+    /// ECN-capable transport, codepoint 0.
+    const ECT0: libc::c_int = 0b10;
+
+    fn setsockopt<T>(
+        io: &UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: T,
+    ) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                io.as_raw_fd(),
+                level,
+                name,
+                &value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn enable_gro(io: &UdpSocket) -> io::Result<()> {
+        setsockopt(io, libc::SOL_UDP, libc::UDP_GRO, 1 as libc::c_int)
+    }
+
+    /// Lets the kernel hold back datagrams until the time in their `SCM_TXTIME` message.
+    pub fn enable_txtime(io: &UdpSocket) -> io::Result<()> {
+        let config = libc::sock_txtime {
+            clockid: libc::CLOCK_MONOTONIC,
+            flags: 0,
+        };
+        setsockopt(io, libc::SOL_SOCKET, SO_TXTIME, config)
+    }
+
+    /// Marks every outgoing datagram with ECT(0) and reports the ECN bits of received ones.
+    ///
+    /// The DSCP the socket was configured with is kept.
+    pub fn enable_ecn(io: &UdpSocket) -> io::Result<()> {
+        if io.local_addr()?.is_ipv6() {
+            setsockopt(
+                io,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_RECVTCLASS,
+                1 as libc::c_int,
+            )?;
+            let tclass = getsockopt(io, libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?;
+            setsockopt(
+                io,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                (tclass & !0b11) | ECT0,
+            )?;
+            // IPv4 traffic of a dual-stack socket, this fails if the socket is IPv6 only.
+            let _ = setsockopt(io, libc::IPPROTO_IP, libc::IP_RECVTOS, 1 as libc::c_int);
+            let _ = setsockopt(io, libc::IPPROTO_IP, libc::IP_TOS, (tclass & !0b11) | ECT0);
+        } else {
+            setsockopt(io, libc::IPPROTO_IP, libc::IP_RECVTOS, 1 as libc::c_int)?;
+            let tos = getsockopt(io, libc::IPPROTO_IP, libc::IP_TOS)?;
+            setsockopt(io, libc::IPPROTO_IP, libc::IP_TOS, (tos & !0b11) | ECT0)?;
+        }
+        Ok(())
+    }
+
+    fn getsockopt(
+        io: &UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+    ) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                io.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result == 0 {
+            Ok(value)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn gso_supported(io: &UdpSocket) -> bool {
+        getsockopt(io, libc::SOL_UDP, libc::UDP_SEGMENT).is_ok()
+    }
+
+    pub fn send(io: &UdpSocket, transmits: &[Transmit]) -> io::Result<usize> {
+        let count = transmits.len().min(SEND_BATCH_SIZE);
+        let mut names: [libc::sockaddr_storage; SEND_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; SEND_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [Control; SEND_BATCH_SIZE] = [[0; 8]; SEND_BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; SEND_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (i, transmit) in transmits[..count].iter().enumerate() {
+            iovecs[i] = libc::iovec {
+                iov_base: transmit.contents.as_ptr() as *mut libc::c_void,
+                iov_len: transmit.contents.len(),
+            };
+            let hdr = &mut hdrs[i].msg_hdr;
+            hdr.msg_namelen = to_sockaddr(transmit.to, &mut names[i]);
+            hdr.msg_name = &mut names[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_iovlen = 1;
+            let mut control_len = 0;
+            if transmit.segment_size.is_some() {
+                control_len += unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as libc::c_uint) };
+            }
+            if transmit.txtime.is_some() {
+                control_len += unsafe { libc::CMSG_SPACE(mem::size_of::<u64>() as libc::c_uint) };
+            }
+            if control_len > 0 {
+                hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_controllen = control_len as _;
+                unsafe {
+                    let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    if let Some(segment_size) = transmit.segment_size {
+                        cmsg = write_cmsg(
+                            hdr,
+                            cmsg,
+                            libc::SOL_UDP,
+                            libc::UDP_SEGMENT,
+                            segment_size as u16,
+                        );
+                    }
+                    if let Some(at) = transmit.txtime {
+                        write_cmsg(hdr, cmsg, libc::SOL_SOCKET, SCM_TXTIME, monotonic_nanos(at));
+                    }
+                }
+            }
+        }
+
+        let sent = unsafe { libc::sendmmsg(io.as_raw_fd(), hdrs.as_mut_ptr(), count as _, 0) };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    pub fn recv(io: &UdpSocket, bufs: &mut [Vec<u8>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let count = bufs.len().min(meta.len()).min(RECV_BATCH_SIZE);
+        let mut names: [libc::sockaddr_storage; RECV_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; RECV_BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [Control; RECV_BATCH_SIZE] = [[0; 8]; RECV_BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; RECV_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for i in 0..count {
+            iovecs[i] = libc::iovec {
+                iov_base: bufs[i].as_mut_ptr() as *mut libc::c_void,
+                iov_len: bufs[i].len(),
+            };
+            let hdr = &mut hdrs[i].msg_hdr;
+            hdr.msg_name = &mut names[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = mem::size_of::<Control>() as _;
+        }
+
+        let received = unsafe {
+            libc::recvmmsg(
+                io.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                count as _,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for i in 0..received as usize {
+            let len = hdrs[i].msg_len as usize;
+            let (stride, ecn) = unsafe { parse_control(&hdrs[i].msg_hdr) };
+            meta[i] = RecvMeta {
+                addr: from_sockaddr(&names[i])?,
+                len,
+                stride: stride.unwrap_or(len),
+                ecn,
+            };
+        }
+        Ok(received as usize)
+    }
+
+    /// Writes a control message into the free `cmsg` and returns the next free one.
+    unsafe fn write_cmsg<T>(
+        hdr: &libc::msghdr,
+        cmsg: *mut libc::cmsghdr,
+        level: libc::c_int,
+        ty: libc::c_int,
+        value: T,
+    ) -> *mut libc::cmsghdr {
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = ty;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as libc::c_uint) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut T, value);
+        libc::CMSG_NXTHDR(hdr, cmsg)
+    }
+
+    /// Converts `at` to the clock `SO_TXTIME` was configured with.
+    fn monotonic_nanos(at: Instant) -> u64 {
+        let mut now: libc::timespec = unsafe { mem::zeroed() };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        let now = now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64;
+        now + at.saturating_duration_since(Instant::now()).as_nanos() as u64
+    }
+
+    /// Returns the GRO segment size and the ECN bits from the control messages of a datagram.
+    unsafe fn parse_control(hdr: &libc::msghdr) -> (Option<usize>, u8) {
+        let mut segment_size = None;
+        let mut ecn = 0;
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_UDP, libc::UDP_GRO) => {
+                    segment_size = Some(ptr::read_unaligned(data as *const libc::c_int) as usize)
+                }
+                (libc::IPPROTO_IP, libc::IP_TOS) => ecn = ptr::read_unaligned(data) & 0b11,
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    ecn = ptr::read_unaligned(data as *const libc::c_int) as u8 & 0b11
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+        (segment_size, ecn)
+    }
+
+    fn to_sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                };
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr = libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                };
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                    u16::from_be(sin.sin_port),
+                )
+                .into())
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )
+                .into())
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported address family {family}"),
+            )),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use tokio::net::UdpSocket;
+
+    use super::{bind, Datagram, SendBatch};
+    #[cfg(all(feature = "udp-offload", target_os = "linux"))]
+    use super::{configure, RecvBatch};
+    #[cfg(all(feature = "udp-offload", target_os = "linux"))]
+    use crate::config::Settings;
+    use crate::config::{Pacing, SocketOptions};
+
+    #[tokio::test]
+    async fn groups_equal_datagrams() {
+        let io = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut batch = SendBatch::new(&io, Pacing::Disabled);
+        batch.gso_segments = 64;
+        let a = "127.0.0.1:1000".parse().unwrap();
+        let b = "127.0.0.1:2000".parse().unwrap();
+        let mut start = 0;
+        for (len, to) in [(1200, a), (1200, a), (800, a), (1200, a), (1200, b)] {
+            batch.datagrams.push(Datagram {
+                start,
+                len,
+                to,
+                at: Instant::now(),
+            });
+            start += len;
+        }
+
+        let transmits = batch.transmits(Instant::now());
+        let runs: Vec<_> = transmits
+            .iter()
+            .map(|transmit| (transmit.contents.len(), transmit.segment_size, transmit.to))
+            .collect();
+        // A shorter datagram ends a run, so does a different destination.
+        assert_eq!(
+            runs,
+            [(3200, Some(1200), a), (1200, None, a), (1200, None, b),]
+        );
+    }
+
+    #[tokio::test]
+    async fn holds_back_paced_datagram() {
+        let io = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut batch = SendBatch::new(&io, Pacing::Timer);
+        let to = "127.0.0.1:1000".parse().unwrap();
+        let now = Instant::now();
+        for (i, at) in [now, now + Duration::from_millis(20)]
+            .into_iter()
+            .enumerate()
+        {
+            batch.datagrams.push(Datagram {
+                start: i * 1200,
+                len: 1200,
+                to,
+                at,
+            });
+        }
+
+        assert_eq!(batch.transmits(now).len(), 1);
+        assert_eq!(batch.transmits(now + Duration::from_millis(20)).len(), 1);
+        assert_eq!(
+            batch.transmits(now + Duration::from_millis(20))[0]
+                .contents
+                .len(),
+            2400
+        );
+    }
+
+    #[cfg(all(feature = "udp-offload", target_os = "linux"))]
+    #[tokio::test]
+    async fn ecn_marks_round_trip() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for io in [&sender, &receiver] {
+            let mut settings = Settings {
+                ecn: true,
+                ..Default::default()
+            };
+            configure(io, &mut settings);
+            assert!(settings.ecn);
+        }
+
+        sender
+            .send_to(b"marked", receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut batch = RecvBatch::new();
+        let count = std::future::poll_fn(|cx| batch.poll_recv(&receiver, cx))
+            .await
+            .unwrap();
+        let (meta, datagram) = batch.datagrams(count).next().unwrap();
+        assert_eq!(datagram, b"marked");
+        assert_eq!(meta.ecn, 0b10);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_with_reuse_port() {
+        let options = SocketOptions {
+            reuse_port: true,
+            recv_buffer_size: Some(1024 * 1024),
+            ..Default::default()
+        };
+        let first = bind("127.0.0.1:0", &options).await.unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(addr, &options).await.unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "tls")]
+use boring::ssl::{AlpnError, SslContextBuilder};
 #[cfg(feature = "key-gen")]
 use boring::{
     asn1::Asn1Time,
@@ -5,12 +7,404 @@ use boring::{
     hash::MessageDigest,
     pkey::PKey,
     rsa::Rsa,
+    ssl::SslMethod,
     x509::extension::{AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectKeyIdentifier},
 };
 
+use crate::error::Result;
+use crate::metrics::{Metrics, NoMetrics};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// UDP payload size `default()` configures, used as long as path MTU discovery is off.
 pub const MAX_DATAGRAM_SIZE: usize = 1350;
 pub const STREAM_BUFFER_SIZE: usize = 64 * 1024;
 
+/// Settings of a `QuicListener` or `QuicSocket` that are not covered by `quiche::Config`.
+#[derive(Clone)]
+pub struct Settings {
+    /// Where to write a qlog trace of every connection to, disabled if `None`.
+    #[cfg(feature = "qlog")]
+    pub qlog: Option<Qlog>,
+    /// Where to log the TLS secrets of every connection to, disabled if `None`.
+    pub keylog: Option<Keylog>,
+    /// Receives counters about the listener and the connections, see [`Metrics`].
+    pub metrics: Arc<dyn Metrics>,
+    /// Capacities of the internal queues.
+    pub limits: Limits,
+    /// Whether packets are held back until the release time quiche's pacer assigns them.
+    /// `None` keeps the pacing of the `quiche::Config` and sends packets right away.
+    pub pacing: Option<Pacing>,
+    /// Marks outgoing packets as ECN-capable and counts the ECN codepoints of received ones,
+    /// see `ConnectionStats::ecn`. Needs the `udp-offload` feature on Linux.
+    ///
+    /// quiche does not take ECN feedback yet, so CE marks are counted but do not reduce the
+    /// congestion window.
+    pub ecn: bool,
+    /// Options of the socket that is created by `bind_with_settings`.
+    pub socket: SocketOptions,
+    /// Size of the packets after the handshake, `None` keeps the one of the `quiche::Config`.
+    pub pmtud: Option<Pmtud>,
+    /// Congestion controller of every connection, `None` keeps the one of the `quiche::Config`.
+    pub congestion_control: Option<CongestionControl>,
+    /// Chooses the congestion controller of every connection a `QuicListener` accepts,
+    /// overriding `congestion_control`. Ignored by a `QuicSocket`.
+    pub select_congestion_control: Option<SelectCongestionControl>,
+    /// Closes a connection after it received nothing for this long, `None` keeps the timeout
+    /// of the `quiche::Config` and zero disables it. The shorter timeout of both ends applies.
+    pub idle_timeout: Option<Duration>,
+    /// Sends an ack-eliciting packet at this interval, so that an idle connection neither times
+    /// out nor loses its NAT binding. Has to be shorter than the idle timeout to be effective.
+    /// Can be changed per connection with `QuicConnection::set_keep_alive`.
+    pub keep_alive: Option<Duration>,
+    /// Application protocols in order of preference, `None` keeps the ones of the `quiche::Config`.
+    /// A client offers them in this order, a server accepts the first one the client offered
+    /// that is in this list, unless `select_alpn` is set.
+    pub alpn: Option<Vec<Vec<u8>>>,
+    /// Lets a server choose among the protocols a client offered instead, which needs `alpn`.
+    /// It is installed by `with_alpn_selection`, the config has to be built with it.
+    pub select_alpn: Option<SelectAlpn>,
+}
+
+/// Called with the address of a new peer before its connection is created.
+pub type SelectCongestionControl = Arc<dyn Fn(SocketAddr) -> CongestionControl + Send + Sync>;
+
+/// Called with the protocols a client offered, in the client's order, returns the index of the
+/// chosen one. `None` fails the handshake with `no_application_protocol`.
+pub type SelectAlpn = Arc<dyn Fn(&[&[u8]]) -> Option<usize> + Send + Sync>;
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "qlog")]
+            qlog: None,
+            keylog: None,
+            metrics: Arc::new(NoMetrics),
+            limits: Limits::default(),
+            pacing: None,
+            ecn: false,
+            socket: SocketOptions::default(),
+            pmtud: None,
+            congestion_control: None,
+            select_congestion_control: None,
+            idle_timeout: None,
+            keep_alive: None,
+            alpn: None,
+            select_alpn: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Applies the parts of the settings that are implemented by quiche to `config`.
+    pub(crate) fn apply(&self, config: &mut quiche::Config) -> Result<()> {
+        if self.keylog.is_some() {
+            config.log_keys();
+        }
+        if let Some(pacing) = self.pacing {
+            config.enable_pacing(pacing != Pacing::Disabled);
+        }
+        if let Some(pmtud) = self.pmtud {
+            pmtud.apply(config)?;
+        }
+        if let Some(congestion_control) = self.congestion_control {
+            congestion_control.apply(config);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.set_max_idle_timeout(idle_timeout.as_millis() as u64);
+        }
+        if self.select_alpn.is_some() {
+            // Setting the protocols would replace the selection `with_alpn_selection` installed.
+            if self.alpn.is_none() {
+                let message = "Settings::select_alpn needs Settings::alpn";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            }
+        } else if let Some(alpn) = &self.alpn {
+            let alpn: Vec<&[u8]> = alpn.iter().map(Vec::as_slice).collect();
+            config.set_application_protos(&alpn)?;
+        }
+        Ok(())
+    }
+}
+
+/// Capacities of the queues between the socket, the connection drivers and the streams.
+///
+/// These bound the memory a slow consumer or a flood of packets can use.
+/// Once a queue is full the listener drops packets, while connections stop reading from or
+/// accepting writes for the affected streams until there is room again,
+/// which slows down the peer through QUIC's flow control.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Connections waiting to be returned by `QuicListener::accept`.
+    /// Initials of new connections are dropped while this is full.
+    /// Also bounds the connections `QuicListener::accept_alpn` sets aside.
+    pub accept_queue: usize,
+    /// Packets waiting to be processed by a connection.
+    /// Further packets for the connection are dropped while this is full.
+    pub connection_packets: usize,
+    /// Messages waiting to be processed by a connection, most of them carry at most
+    /// `STREAM_BUFFER_SIZE` bytes of stream data. Writes wait while this is full.
+    /// Applies separately to the stream data and to all other requests, like opening a stream.
+    pub connection_messages: usize,
+    /// Stream data in bytes that a connection keeps on top of what quiche has accepted.
+    /// The connection stops taking stream data while more than this is queued,
+    /// other requests are still served.
+    pub pending_send_bytes: usize,
+    /// Incoming streams waiting to be returned by `QuicConnection::incoming`.
+    /// New streams are not read from while this is full.
+    pub incoming_streams: usize,
+    /// Chunks of at most `STREAM_BUFFER_SIZE` bytes received for a stream but not read yet.
+    /// The stream is not read from while this is full.
+    pub stream_messages: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            accept_queue: 128,
+            connection_packets: 256,
+            connection_messages: 64,
+            pending_send_bytes: 1024 * 1024,
+            incoming_streams: 128,
+            stream_messages: 16,
+        }
+    }
+}
+
+/// How packets are spread out according to quiche's pacer, see `quiche::SendInfo::at`.
+///
+/// Without pacing every packet the congestion window allows is sent right away,
+/// which can overflow the queues of shallow-buffered links.
+/// Sets `quiche::Config::enable_pacing`, so `Disabled` also turns off quiche's pacer,
+/// which is on by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// Packets are sent as soon as quiche produces them.
+    #[default]
+    Disabled,
+    /// The connection waits for the release time of a packet before sending it.
+    Timer,
+    /// The release time is attached to the packet with `SO_TXTIME`, so that the kernel holds it back.
+    /// This needs the `udp-offload` feature on Linux and a qdisc that honors it, like `fq`.
+    /// Falls back to `Timer` if the socket does not support it.
+    Kernel,
+}
+
+/// Congestion control algorithm of a connection, see `quiche::CongestionControlAlgorithm`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CcAlgorithm {
+    Reno,
+    /// quiche's default.
+    #[default]
+    Cubic,
+    /// Paces by the estimated bottleneck bandwidth instead of reacting to loss,
+    /// which suits bulk transfers over lossy or deep-buffered paths.
+    Bbr2,
+}
+
+impl From<CcAlgorithm> for quiche::CongestionControlAlgorithm {
+    fn from(algorithm: CcAlgorithm) -> Self {
+        match algorithm {
+            CcAlgorithm::Reno => Self::Reno,
+            CcAlgorithm::Cubic => Self::CUBIC,
+            CcAlgorithm::Bbr2 => Self::BBR2,
+        }
+    }
+}
+
+/// The congestion controller of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionControl {
+    pub algorithm: CcAlgorithm,
+    /// Whether slow start exits early with HyStart++ (RFC 9406), before it causes losses.
+    /// Only used by Reno and CUBIC.
+    pub hystart: bool,
+}
+
+impl Default for CongestionControl {
+    fn default() -> Self {
+        Self {
+            algorithm: CcAlgorithm::default(),
+            hystart: true,
+        }
+    }
+}
+
+impl CongestionControl {
+    pub(crate) fn apply(self, config: &mut quiche::Config) {
+        config.set_cc_algorithm(self.algorithm.into());
+        config.enable_hystart(self.hystart);
+    }
+}
+
+/// Size of the UDP payloads sent once the handshake is done, the handshake itself always uses
+/// [`Pmtud::MIN_SIZE`].
+///
+/// Every size is also announced to the peer as the largest one received. Both ends need a value
+/// this large for it to be used, quiche caps it at 16383 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pmtud {
+    /// Datagram Packetization Layer Path MTU Discovery (RFC 8899), see
+    /// `quiche::Config::discover_pmtu`.
+    ///
+    /// quiche sends probes to find the largest UDP payload the path carries, between
+    /// [`Pmtud::MIN_SIZE`] and `max_size`. Lost probes narrow the search,
+    /// so constrained paths keep working with smaller packets.
+    Discover { max_size: usize },
+    /// Sends payloads of `size` without probing, for paths known to carry them.
+    Fixed { size: usize },
+}
+
+impl Pmtud {
+    /// QUIC's minimum UDP payload, which every path has to carry.
+    pub const MIN_SIZE: usize = quiche::MIN_CLIENT_INITIAL_LEN;
+
+    fn apply(self, config: &mut quiche::Config) -> Result<()> {
+        let (size, discover) = match self {
+            Pmtud::Discover { max_size } => (max_size, true),
+            Pmtud::Fixed { size } => (size, false),
+        };
+        if size < Self::MIN_SIZE {
+            let message = "Pmtud sizes have to be at least Pmtud::MIN_SIZE";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        config.discover_pmtu(discover);
+        config.set_max_send_udp_payload_size(size);
+        config.set_max_recv_udp_payload_size(size);
+        Ok(())
+    }
+}
+
+impl Default for Pmtud {
+    /// Discovers up to a 1500 byte Ethernet MTU with an IPv6 header.
+    fn default() -> Self {
+        Self::Discover { max_size: 1452 }
+    }
+}
+
+/// Options applied to the UDP socket when a `QuicListener` or `QuicSocket` binds it.
+///
+/// Everything left at its default keeps the system default.
+/// Sockets passed to `from_socket` or `from_std` are used as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOptions {
+    /// Sets `SO_REUSEADDR`.
+    pub reuse_address: bool,
+    /// Sets `SO_REUSEPORT`, so that several listeners can share a port and the kernel spreads
+    /// the peers between them. Only supported on Unix.
+    pub reuse_port: bool,
+    /// Size of the kernel's receive buffer in bytes (`SO_RCVBUF`).
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the kernel's send buffer in bytes (`SO_SNDBUF`).
+    pub send_buffer_size: Option<usize>,
+    /// DSCP of the outgoing packets, the upper six bits of `IP_TOS` or `IPV6_TCLASS`.
+    pub dscp: Option<u8>,
+    /// Whether a socket bound to an IPv6 address only handles IPv6 (`IPV6_V6ONLY`).
+    pub only_v6: Option<bool>,
+}
+
+/// The side of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Client => write!(f, "client"),
+            Role::Server => write!(f, "server"),
+        }
+    }
+}
+
+/// Destination of the TLS secrets in the `SSLKEYLOGFILE` format,
+/// which lets Wireshark decrypt captured packets.
+///
+/// Anyone with access to these secrets can decrypt the traffic, so this is meant for debugging only.
+#[derive(Clone)]
+pub enum Keylog {
+    /// Appends to the file named by the `SSLKEYLOGFILE` environment variable, if it is set.
+    Env,
+    /// Appends to this file.
+    File(PathBuf),
+    /// Writes to a writer that is shared by all connections.
+    Writer(Arc<Mutex<dyn Write + Send>>),
+}
+
+impl Keylog {
+    /// Returns `None` if `Env` was chosen but `SSLKEYLOGFILE` is not set.
+    pub(crate) fn writer(&self) -> std::io::Result<Option<Box<dyn Write + Send + Sync>>> {
+        let path = match self {
+            Keylog::Env => match std::env::var_os("SSLKEYLOGFILE") {
+                Some(path) => PathBuf::from(path),
+                None => return Ok(None),
+            },
+            Keylog::File(path) => path.clone(),
+            Keylog::Writer(writer) => return Ok(Some(Box::new(SharedWriter(writer.clone())))),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Some(Box::new(file)))
+    }
+}
+
+/// Gives every connection its own handle to a writer.
+struct SharedWriter(Arc<Mutex<dyn Write + Send>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        // Keep the lines of concurrent connections from interleaving.
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Creates the writer a qlog trace is written to, from the connection's trace id and role.
+#[cfg(feature = "qlog")]
+pub type QlogWriterFn =
+    dyn Fn(&str, Role) -> std::io::Result<Box<dyn Write + Send + Sync>> + Send + Sync;
+
+/// Output of the qlog traces, which can be inspected with [qvis](https://qvis.quictools.info/).
+#[cfg(feature = "qlog")]
+#[derive(Clone)]
+pub enum Qlog {
+    /// Writes one `<trace id>-<role>.sqlog` file per connection into this directory.
+    Dir(PathBuf),
+    /// Writes each connection's trace to the writer returned by the function.
+    Writer(Arc<QlogWriterFn>),
+}
+
+#[cfg(feature = "qlog")]
+impl Qlog {
+    pub(crate) fn writer(
+        &self,
+        trace_id: &str,
+        role: Role,
+    ) -> std::io::Result<Box<dyn Write + Send + Sync>> {
+        match self {
+            Qlog::Dir(dir) => {
+                let file = std::fs::File::create(dir.join(format!("{trace_id}-{role}.sqlog")))?;
+                Ok(Box::new(std::io::BufWriter::new(file)))
+            }
+            Qlog::Writer(writer) => writer(trace_id, role),
+        }
+    }
+}
+
 #[cfg(feature = "key-gen")]
 pub fn generate_local_certificate() -> (Vec<u8>, Vec<u8>) {
     let rsa = Rsa::generate(2048).unwrap();
@@ -70,30 +464,22 @@ pub fn generate_local_certificate() -> (Vec<u8>, Vec<u8>) {
     (priv_key, x509.build().to_pem().unwrap())
 }
 
+/// Creates a context with a freshly generated certificate for `localhost`,
+/// for testing only.
 #[cfg(feature = "key-gen")]
-pub fn default() -> quiche::Config {
+pub fn local_ssl_context() -> SslContextBuilder {
     let cert = generate_local_certificate();
-    let mut ctx = boring::ssl::SslContextBuilder::new(boring::ssl::SslMethod::tls()).unwrap();
+    let mut ctx = SslContextBuilder::new(SslMethod::tls()).unwrap();
     let key = boring::pkey::PKey::private_key_from_pem(&cert.0).unwrap();
     ctx.set_private_key(key.as_ref()).unwrap();
     let x509 = boring::x509::X509::from_pem(&cert.1).unwrap();
     ctx.set_certificate(x509.as_ref()).unwrap();
-    let mut config =
-        quiche::Config::with_boring_ssl_ctx_builder(quiche::PROTOCOL_VERSION, ctx).unwrap();
-    config
-        .set_application_protos(quiche::h3::APPLICATION_PROTOCOL)
-        .unwrap();
-    config.set_max_idle_timeout(5000);
-    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_initial_max_data(10_000_000);
-    config.set_initial_max_stream_data_bidi_local(1_000_000);
-    config.set_initial_max_stream_data_bidi_remote(1_000_000);
-    config.set_initial_max_stream_data_uni(1_000_000);
-    config.set_initial_max_streams_bidi(100);
-    config.set_initial_max_streams_uni(100);
-    config.set_disable_active_migration(true);
-    config
+    ctx
+}
+
+#[cfg(feature = "key-gen")]
+pub fn default() -> quiche::Config {
+    with_ssl_context(local_ssl_context()).unwrap()
 }
 
 #[cfg(not(feature = "key-gen"))]
@@ -102,6 +488,64 @@ pub fn default() -> quiche::Config {
     config
         .set_application_protos(quiche::h3::APPLICATION_PROTOCOL)
         .unwrap();
+    set_defaults(&mut config);
+    config
+}
+
+/// Creates a config like `default()` that takes its certificate and everything else TLS from `ctx`,
+/// e.g. one with a `CertResolver` installed.
+#[cfg(feature = "tls")]
+pub fn with_ssl_context(ctx: SslContextBuilder) -> Result<quiche::Config> {
+    let mut config = quiche::Config::with_boring_ssl_ctx_builder(quiche::PROTOCOL_VERSION, ctx)?;
+    config.set_application_protos(quiche::h3::APPLICATION_PROTOCOL)?;
+    set_defaults(&mut config);
+    Ok(config)
+}
+
+/// Creates a config like `default()` whose server side lets `settings.select_alpn` choose the
+/// application protocol, instead of taking the first one the client offered that is also in
+/// `settings.alpn`. As a client the config offers `settings.alpn` in the given order.
+///
+/// Fails with `InvalidInput` unless both are set.
+/// `Settings::apply` leaves the protocols of the config alone while `select_alpn` is set.
+#[cfg(feature = "tls")]
+pub fn with_alpn_selection(
+    mut ctx: SslContextBuilder,
+    settings: &Settings,
+) -> Result<quiche::Config> {
+    let (Some(alpn), Some(select)) = (&settings.alpn, settings.select_alpn.clone()) else {
+        let message = "with_alpn_selection needs Settings::alpn and Settings::select_alpn";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+    };
+    let mut wire = Vec::new();
+    for protocol in alpn {
+        let len = u8::try_from(protocol.len()).map_err(|_| quiche::Error::TlsFail)?;
+        wire.push(len);
+        wire.extend_from_slice(protocol);
+    }
+    ctx.set_alpn_protos(&wire)
+        .map_err(|_| quiche::Error::TlsFail)?;
+    ctx.set_alpn_select_callback(move |_, mut offered| {
+        let mut protocols = Vec::new();
+        while let Some((&len, rest)) = offered.split_first() {
+            if rest.len() < len as usize {
+                break;
+            }
+            let (protocol, rest) = rest.split_at(len as usize);
+            protocols.push(protocol);
+            offered = rest;
+        }
+        select(&protocols)
+            .and_then(|index| protocols.get(index).copied())
+            .ok_or(AlpnError::ALERT_FATAL)
+    });
+    let mut config = quiche::Config::with_boring_ssl_ctx_builder(quiche::PROTOCOL_VERSION, ctx)?;
+    set_defaults(&mut config);
+    Ok(config)
+}
+
+/// Everything `default()` configures besides TLS.
+fn set_defaults(config: &mut quiche::Config) {
     config.set_max_idle_timeout(5000);
     config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
     config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
@@ -112,5 +556,172 @@ pub fn default() -> quiche::Config {
     config.set_initial_max_streams_bidi(100);
     config.set_initial_max_streams_uni(100);
     config.set_disable_active_migration(true);
-    config
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::sync::Arc;
+
+    use super::{local_ssl_context, with_alpn_selection, Pmtud, SelectAlpn, Settings};
+    use crate::test_util::settings;
+    use crate::{QuicListener, QuicSocket};
+
+    /// Collects what a connection writes, so that it can be inspected after the test.
+    #[cfg(feature = "qlog")]
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "qlog")]
+    impl std::io::Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "qlog")]
+    #[tokio::test]
+    async fn writes_qlog_per_connection() {
+        use std::sync::Mutex;
+
+        use super::{Qlog, Role};
+        use crate::test_util::connection_pair_with;
+
+        let traces: Arc<Mutex<Vec<(String, Role, Recorder)>>> = Default::default();
+        let created = traces.clone();
+        let (client, server) = connection_pair_with(Settings {
+            qlog: Some(Qlog::Writer(Arc::new(move |trace_id: &str, role| {
+                let recorder = Recorder::default();
+                created
+                    .lock()
+                    .unwrap()
+                    .push((trace_id.to_string(), role, recorder.clone()));
+                Ok(Box::new(recorder) as Box<dyn std::io::Write + Send + Sync>)
+            }))),
+            ..settings()
+        })
+        .await;
+        client.ping().await.unwrap();
+
+        let traces = traces.lock().unwrap();
+        let mut roles: Vec<Role> = traces.iter().map(|(_, role, _)| *role).collect();
+        roles.sort_by_key(|role| *role == Role::Server);
+        assert_eq!(roles, [Role::Client, Role::Server]);
+        for (trace_id, role, recorder) in traces.iter() {
+            let expected = match role {
+                Role::Client => &client.handshake_info().trace_id,
+                Role::Server => &server.handshake_info().trace_id,
+            };
+            assert_eq!(trace_id, expected);
+            let trace = String::from_utf8(recorder.0.lock().unwrap().clone()).unwrap();
+            assert!(trace.contains("qlog_version"), "{role} trace has no header");
+            assert!(trace.contains("packet_sent"), "{role} trace has no events");
+        }
+    }
+
+    #[tokio::test]
+    async fn server_selects_alpn() {
+        let alpn: Vec<Vec<u8>> = vec![b"proto-a".to_vec(), b"proto-b".to_vec()];
+        let server_settings = Settings {
+            alpn: Some(alpn.clone()),
+            // Prefers proto-b, although the client lists proto-a first.
+            select_alpn: Some(Arc::new(|offered: &[&[u8]]| {
+                offered.iter().position(|protocol| *protocol == b"proto-b")
+            })),
+            ..settings()
+        };
+        let config = with_alpn_selection(local_ssl_context(), &server_settings).unwrap();
+        let mut listener =
+            QuicListener::bind_with_settings("127.0.0.1:0", config, vec![7; 16], server_settings)
+                .await
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut socket = QuicSocket::bind_with_settings(
+            "127.0.0.1:0",
+            super::default(),
+            Settings {
+                alpn: Some(alpn),
+                ..settings()
+            },
+        )
+        .await
+        .unwrap();
+        let (client, server) =
+            tokio::join!(socket.connect(Some("localhost"), addr), listener.accept());
+        let (client, server) = (client.unwrap(), server.unwrap());
+
+        assert_eq!(client.handshake_info().alpn, b"proto-b");
+        assert_eq!(server.handshake_info().alpn, b"proto-b");
+    }
+
+    #[test]
+    fn select_alpn_needs_alpn() {
+        let select_alpn: SelectAlpn = Arc::new(|_: &[&[u8]]| Some(0));
+        let settings = Settings {
+            select_alpn: Some(select_alpn),
+            ..settings()
+        };
+        assert!(settings.apply(&mut super::default()).is_err());
+        assert!(with_alpn_selection(local_ssl_context(), &settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_connections_by_alpn() {
+        let mut listener = QuicListener::bind_with_settings(
+            "127.0.0.1:0",
+            super::default(),
+            vec![7; 16],
+            Settings {
+                alpn: Some(vec![b"proto-a".to_vec(), b"proto-b".to_vec()]),
+                ..settings()
+            },
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = |alpn: &'static [u8]| {
+            tokio::spawn(async move {
+                let mut socket = QuicSocket::bind_with_settings(
+                    "127.0.0.1:0",
+                    super::default(),
+                    Settings {
+                        alpn: Some(vec![alpn.to_vec()]),
+                        ..settings()
+                    },
+                )
+                .await
+                .unwrap();
+                socket.connect(Some("localhost"), addr).await.unwrap()
+            })
+        };
+        let client_a = connect(b"proto-a");
+        let client_b = connect(b"proto-b");
+
+        // Whichever arrives first, proto-a is set aside until it is asked for.
+        let server_b = listener.accept_alpn(b"proto-b").await.unwrap();
+        let server_a = listener.accept_alpn(b"proto-a").await.unwrap();
+        assert_eq!(server_b.handshake_info().alpn, b"proto-b");
+        assert_eq!(server_a.handshake_info().alpn, b"proto-a");
+        assert_eq!(client_a.await.unwrap().handshake_info().alpn, b"proto-a");
+        assert_eq!(client_b.await.unwrap().handshake_info().alpn, b"proto-b");
+    }
+
+    #[test]
+    fn rejects_pmtud_sizes_below_minimum() {
+        let apply = |pmtud| {
+            let settings = Settings {
+                pmtud: Some(pmtud),
+                ..settings()
+            };
+            settings.apply(&mut super::default())
+        };
+        assert!(apply(Pmtud::Discover { max_size: 4000 }).is_ok());
+        assert!(apply(Pmtud::Fixed { size: 1300 }).is_ok());
+        assert!(apply(Pmtud::Discover { max_size: 1000 }).is_err());
+        assert!(apply(Pmtud::Fixed { size: 1000 }).is_err());
+    }
 }
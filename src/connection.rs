@@ -1,16 +1,17 @@
-use log::trace;
-use std::{collections::HashMap, marker::PhantomData, sync::Arc};
-use bytes::BytesMut;
+use std::{collections::HashMap, io, marker::PhantomData, net::SocketAddr, time::Duration};
 use tokio::{
     sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-        Mutex,
+        mpsc::{self, Receiver, Sender},
+        oneshot,
     },
     task::JoinHandle,
 };
 
-use crate::backend::Driver;
+use crate::backend::{Driver, KeepAlive};
+use crate::config::Settings;
+use crate::stats::ConnectionStats;
 use crate::stream::{BidiStream, Readable, UniStream, Writeable};
+use crate::trace::{self, event};
 use crate::{
     backend::{client, server},
     error::Result,
@@ -43,15 +44,13 @@ impl Incoming {
     ) -> Option<Self> {
         if let Some(stream) = stream {
             Some(match (stream.id & 0b11, is_server) {
-                (0b10, true) | (0b11, false) => {
-                    Self::Uni(UniStream::new(stream.id, stream.rx, stream.tx))
-                }
-                (_, _) => Self::Bidi(BidiStream {
-                    id: stream.id,
-                    rx: stream.rx,
-                    tx: stream.tx,
-                    buffer_read: BytesMut::with_capacity(u16::MAX as usize),
-                }),
+                (0b10, true) | (0b11, false) => Self::Uni(UniStream::new(
+                    stream.id,
+                    stream.rx,
+                    stream.tx,
+                    stream.control,
+                )),
+                (_, _) => Self::Bidi(BidiStream::from(stream)),
             })
         } else {
             None
@@ -59,7 +58,85 @@ impl Incoming {
     }
 }
 
-type AsyncStreamMap = Arc<Mutex<HashMap<u64, UnboundedSender<Result<Message>>>>>;
+/// What was negotiated during the handshake, see `QuicConnection::handshake_info`.
+///
+/// quiche does not expose the negotiated TLS cipher, so it is not included.
+#[derive(Debug, Clone)]
+pub struct HandshakeInfo {
+    /// The application protocol chosen with ALPN.
+    pub alpn: Vec<u8>,
+    /// The server name the client requested with SNI.
+    pub server_name: Option<String>,
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    /// Identifies the connection in logs and qlog traces.
+    pub trace_id: String,
+    /// Whether a previous TLS session was resumed.
+    pub resumed: bool,
+    /// The limits the peer announced.
+    pub peer_transport_params: TransportParams,
+}
+
+impl HandshakeInfo {
+    /// `addrs` are the local and peer address the connection was created with,
+    /// used if quiche has no active path anymore.
+    fn new(connection: &quiche::Connection, addrs: (SocketAddr, SocketAddr)) -> Self {
+        let (local_addr, peer_addr) = connection
+            .path_stats()
+            .find(|path| path.active)
+            .map_or(addrs, |path| (path.local_addr, path.peer_addr));
+        Self {
+            alpn: connection.application_proto().to_vec(),
+            server_name: connection.server_name().map(str::to_owned),
+            local_addr,
+            peer_addr,
+            trace_id: connection.trace_id().to_owned(),
+            resumed: connection.is_resumed(),
+            peer_transport_params: connection
+                .peer_transport_params()
+                .map(TransportParams::from)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Transport parameters of a peer, see RFC 9000 section 18.2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportParams {
+    /// Zero if the peer does not time out idle connections.
+    pub max_idle_timeout: Duration,
+    pub max_udp_payload_size: u64,
+    pub initial_max_data: u64,
+    pub initial_max_stream_data_bidi_local: u64,
+    pub initial_max_stream_data_bidi_remote: u64,
+    pub initial_max_stream_data_uni: u64,
+    pub initial_max_streams_bidi: u64,
+    pub initial_max_streams_uni: u64,
+    pub max_ack_delay: Duration,
+    pub disable_active_migration: bool,
+    pub active_conn_id_limit: u64,
+    /// `None` if the peer does not accept DATAGRAM frames.
+    pub max_datagram_frame_size: Option<u64>,
+}
+
+impl From<&quiche::TransportParams> for TransportParams {
+    fn from(params: &quiche::TransportParams) -> Self {
+        Self {
+            max_idle_timeout: Duration::from_millis(params.max_idle_timeout),
+            max_udp_payload_size: params.max_udp_payload_size,
+            initial_max_data: params.initial_max_data,
+            initial_max_stream_data_bidi_local: params.initial_max_stream_data_bidi_local,
+            initial_max_stream_data_bidi_remote: params.initial_max_stream_data_bidi_remote,
+            initial_max_stream_data_uni: params.initial_max_stream_data_uni,
+            initial_max_streams_bidi: params.initial_max_streams_bidi,
+            initial_max_streams_uni: params.initial_max_streams_uni,
+            max_ack_delay: Duration::from_millis(params.max_ack_delay),
+            disable_active_migration: params.disable_active_migration,
+            active_conn_id_limit: params.active_conn_id_limit,
+            max_datagram_frame_size: params.max_datagram_frame_size,
+        }
+    }
+}
 
 /// A `QuicConnection` represents a connection to a remote host.
 ///
@@ -75,34 +152,145 @@ type AsyncStreamMap = Arc<Mutex<HashMap<u64, UnboundedSender<Result<Message>>>>>
 pub struct QuicConnection<T: Backend + Send> {
     #[allow(unused)]
     handle: JoinHandle<Result<()>>,
-    stream_map: AsyncStreamMap,
-    // Map each stream to a `Sender`
-    message_send: UnboundedSender<Message>,
+    message_send: Sender<Message>,
+    control_send: Sender<Message>,
     // This is passed to each stream.
-    incoming_recv: UnboundedReceiver<UncheckedQuicStream>,
+    incoming_recv: Receiver<UncheckedQuicStream>,
+    /// Capacity of the channel to every stream that is opened.
+    stream_capacity: usize,
+    handshake_info: HandshakeInfo,
+    span: trace::Span,
     state: PhantomData<T>,
 }
 
+impl<T: Backend + Send> QuicConnection<T> {
+    /// Returns what was negotiated during the handshake.
+    pub fn handshake_info(&self) -> &HandshakeInfo {
+        &self.handshake_info
+    }
+
+    /// Returns a snapshot of the connection's statistics.
+    ///
+    /// Fails if the connection has already been closed.
+    pub async fn stats(&self) -> Result<ConnectionStats> {
+        let (reply, stats) = oneshot::channel();
+        self.control_send
+            .send(Message::Stats(reply))
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected)?;
+        Ok(stats.await.map_err(|_| io::ErrorKind::NotConnected)?)
+    }
+
+    /// Sends an ack-eliciting packet and returns the time until an acknowledgment arrived.
+    ///
+    /// The ping counts as acknowledged once everything sent up to it was acknowledged or declared
+    /// lost. On an otherwise idle connection this is the round-trip time, while other packets are
+    /// in flight it includes the time they queue, see `PathStats::rtt` for a smoothed estimate.
+    /// Fails if the connection is closed before that.
+    pub async fn ping(&self) -> Result<Duration> {
+        let (reply, rtt) = oneshot::channel();
+        self.control_send
+            .send(Message::Ping(reply))
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected)?;
+        Ok(rtt.await.map_err(|_| io::ErrorKind::NotConnected)?)
+    }
+
+    /// Changes the keep-alive interval of this connection, `None` turns keep-alives off.
+    ///
+    /// See `Settings::keep_alive`.
+    pub async fn set_keep_alive(&self, interval: Option<Duration>) -> Result<()> {
+        self.control_send
+            .send(Message::KeepAlive(interval))
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected)?;
+        Ok(())
+    }
+
+    /// Registers a locally opened stream with the driver and returns the receiving end of its data.
+    ///
+    /// A `priority` is set in the same step, so the stream either exists with it or not at all.
+    async fn open(
+        &self,
+        stream_id: u64,
+        priority: Option<(u8, bool)>,
+    ) -> Result<Receiver<Result<Message>>> {
+        let (tx, rx) = mpsc::channel(self.stream_capacity);
+        let (reply, opened) = oneshot::channel();
+        self.control_send
+            .send(Message::Open {
+                stream_id,
+                tx,
+                priority,
+                reply,
+            })
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected)?;
+        opened.await.map_err(|_| io::ErrorKind::NotConnected)??;
+        Ok(rx)
+    }
+
+    async fn open_bidi(&self, id: u64, priority: Option<(u8, bool)>) -> Result<BidiStream> {
+        let rx = self.open(id, priority).await?;
+        let stream = BidiStream::new(id, rx, self.message_send.clone(), self.control_send.clone());
+        self.span
+            .in_scope(|| event!(debug, "Opened bidi stream", stream_id = id));
+        Ok(stream)
+    }
+
+    async fn open_uni(
+        &self,
+        id: u64,
+        priority: Option<(u8, bool)>,
+    ) -> Result<UniStream<Writeable>> {
+        let rx = self.open(id, priority).await?;
+        let stream = UniStream::new(id, rx, self.message_send.clone(), self.control_send.clone());
+        self.span
+            .in_scope(|| event!(debug, "Opened uni stream", stream_id = id));
+        Ok(stream)
+    }
+}
+
 impl QuicConnection<ToClient> {
-    pub(crate) fn new(inner: server::Inner) -> Self {
-        let (message_send, message_recv) = mpsc::unbounded_channel::<Message>();
-        let stream_map: AsyncStreamMap = Arc::new(Mutex::new(HashMap::new()));
-        let (incoming_send, incoming_recv) = mpsc::unbounded_channel();
+    pub(crate) fn new(
+        inner: server::Inner,
+        addrs: (SocketAddr, SocketAddr),
+        span: trace::Span,
+        settings: &Settings,
+    ) -> Self {
+        let limits = settings.limits;
+        let (message_send, message_recv) = mpsc::channel::<Message>(limits.connection_messages);
+        let (control_send, control_recv) = mpsc::channel::<Message>(limits.connection_messages);
+        let (incoming_send, incoming_recv) = mpsc::channel(limits.incoming_streams);
+        let handshake_info = HandshakeInfo::new(&inner.connection, addrs);
 
         let driver = Driver {
             inner,
-            stream_map: stream_map.clone(),
+            streams: HashMap::new(),
             message_recv,
             message_send: message_send.clone(),
+            control_recv,
+            control_send: control_send.clone(),
             incoming_send,
+            pending_send: HashMap::new(),
+            priorities: HashMap::new(),
+            stream_stats: HashMap::new(),
+            blocked_reads: HashMap::new(),
+            metrics: settings.metrics.clone(),
+            limits,
+            keep_alive: settings.keep_alive.map(KeepAlive::new),
+            pings: Vec::new(),
         };
-        let handle = tokio::spawn(driver);
+        let handle = tokio::spawn(trace::instrument(driver, span.clone()));
 
         Self {
             handle,
-            stream_map,
             message_send,
+            control_send,
             incoming_recv,
+            stream_capacity: limits.stream_messages,
+            handshake_info,
+            span,
             state: PhantomData,
         }
     }
@@ -118,20 +306,20 @@ impl QuicConnection<ToClient> {
     /// # Arguments
     /// * `id`: A 62 bit integer.
     pub async fn bidi(&mut self, id: u64) -> Result<BidiStream> {
-        let mut map = self.stream_map.lock().await;
-        let id = (id << 2) | 0b01;
-        if map.contains_key(&id) {
-            return Err(super::error::Error::IdAlreadyTaken(id));
-        }
-        let (tx, rx) = mpsc::unbounded_channel();
-        let stream = BidiStream {
-            id,
-            rx,
-            tx: self.message_send.clone(),
-            buffer_read: BytesMut::with_capacity(u16::MAX as usize),
-        };
-        map.insert(id, tx);
-        Ok(stream)
+        self.open_bidi((id << 2) | 0b01, None).await
+    }
+
+    /// Opens a new bidi stream to the client with the given priority.
+    ///
+    /// See [`BidiStream::set_priority`] for the meaning of `urgency` and `incremental`.
+    pub async fn bidi_with_priority(
+        &mut self,
+        id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> Result<BidiStream> {
+        self.open_bidi((id << 2) | 0b01, Some((urgency, incremental)))
+            .await
     }
 
     /// Opens a new uni stream to the client.
@@ -139,38 +327,63 @@ impl QuicConnection<ToClient> {
     /// # Arguments
     /// * `id`: A 62 bit integer.
     pub async fn uni(&mut self, id: u64) -> Result<UniStream<Writeable>> {
-        let mut map = self.stream_map.lock().await;
-        let id = (id << 2) | 0b11;
-        if map.contains_key(&id) {
-            return Err(super::error::Error::IdAlreadyTaken(id));
-        }
-        let (tx, rx) = mpsc::unbounded_channel();
-        let stream = UniStream::new(id, rx, self.message_send.clone());
-        map.insert(id, tx);
-        Ok(stream)
+        self.open_uni((id << 2) | 0b11, None).await
+    }
+
+    /// Opens a new uni stream to the client with the given priority.
+    ///
+    /// See [`UniStream::set_priority`] for the meaning of `urgency` and `incremental`.
+    pub async fn uni_with_priority(
+        &mut self,
+        id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> Result<UniStream<Writeable>> {
+        self.open_uni((id << 2) | 0b11, Some((urgency, incremental)))
+            .await
     }
 }
 
 impl QuicConnection<ToServer> {
-    pub(crate) fn new(inner: client::Inner) -> Self {
-        let (message_send, message_recv) = mpsc::unbounded_channel::<Message>();
-        let stream_map: AsyncStreamMap = Arc::new(Mutex::new(HashMap::new()));
-        let (incoming_send, incoming_recv) = mpsc::unbounded_channel();
+    pub(crate) fn new(
+        inner: client::Inner,
+        addrs: (SocketAddr, SocketAddr),
+        span: trace::Span,
+        settings: &Settings,
+    ) -> Self {
+        let limits = settings.limits;
+        let (message_send, message_recv) = mpsc::channel::<Message>(limits.connection_messages);
+        let (control_send, control_recv) = mpsc::channel::<Message>(limits.connection_messages);
+        let (incoming_send, incoming_recv) = mpsc::channel(limits.incoming_streams);
+        let handshake_info = HandshakeInfo::new(&inner.connection, addrs);
 
         let driver = Driver {
             inner,
-            stream_map: stream_map.clone(),
+            streams: HashMap::new(),
             message_recv,
             message_send: message_send.clone(),
+            control_recv,
+            control_send: control_send.clone(),
             incoming_send,
+            pending_send: HashMap::new(),
+            priorities: HashMap::new(),
+            stream_stats: HashMap::new(),
+            blocked_reads: HashMap::new(),
+            metrics: settings.metrics.clone(),
+            limits,
+            keep_alive: settings.keep_alive.map(KeepAlive::new),
+            pings: Vec::new(),
         };
-        let handle = tokio::spawn(driver);
+        let handle = tokio::spawn(trace::instrument(driver, span.clone()));
 
         Self {
             handle,
-            stream_map,
             message_send,
+            control_send,
             incoming_recv,
+            stream_capacity: limits.stream_messages,
+            handshake_info,
+            span,
             state: PhantomData,
         }
     }
@@ -186,21 +399,19 @@ impl QuicConnection<ToServer> {
     /// # Arguments
     /// * `id`: A 62 bit integer.
     pub async fn bidi(&mut self, id: u64) -> Result<BidiStream> {
-        let mut map = self.stream_map.lock().await;
-        let id = id << 2;
-        if map.contains_key(&id) {
-            return Err(super::error::Error::IdAlreadyTaken(id));
-        }
-        let (tx, rx) = mpsc::unbounded_channel();
-        let stream = BidiStream {
-            id,
-            rx,
-            tx: self.message_send.clone(),
-            buffer_read: BytesMut::with_capacity(u16::MAX as usize),
-        };
-        map.insert(id, tx);
-        trace!("New bidi stream: {}", stream.id);
-        Ok(stream)
+        self.open_bidi(id << 2, None).await
+    }
+
+    /// Opens a new bidi stream to the server with the given priority.
+    ///
+    /// See [`BidiStream::set_priority`] for the meaning of `urgency` and `incremental`.
+    pub async fn bidi_with_priority(
+        &mut self,
+        id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> Result<BidiStream> {
+        self.open_bidi(id << 2, Some((urgency, incremental))).await
     }
 
     /// Opens a new uni stream to the server.
@@ -208,15 +419,267 @@ impl QuicConnection<ToServer> {
     /// # Arguments
     /// * `id`: A 62 bit integer.
     pub async fn uni(&mut self, id: u64) -> Result<UniStream<Writeable>> {
-        let mut map = self.stream_map.lock().await;
-        let id = (id << 2) | 0b10;
-        if map.contains_key(&id) {
-            return Err(super::error::Error::IdAlreadyTaken(id));
+        self.open_uni((id << 2) | 0b10, None).await
+    }
+
+    /// Opens a new uni stream to the server with the given priority.
+    ///
+    /// See [`UniStream::set_priority`] for the meaning of `urgency` and `incremental`.
+    pub async fn uni_with_priority(
+        &mut self,
+        id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> Result<UniStream<Writeable>> {
+        self.open_uni((id << 2) | 0b10, Some((urgency, incremental)))
+            .await
+    }
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::task::JoinSet;
+
+    use crate::config::{self, CcAlgorithm, CongestionControl, Limits, Settings};
+    use crate::connection::Incoming;
+    use crate::error::Error;
+    use crate::io::TryWrite;
+    use crate::stream::QuicStream;
+    use crate::test_util::{
+        connection_pair, connection_pair_with, connection_pair_with_config, payload, settings,
+    };
+
+    const STREAMS: u64 = 64;
+    const RESPONSE_LEN: usize = 64 * 1024;
+
+    /// Opens streams from several tasks at once while the responses to the earlier ones keep
+    /// the driver busy reading.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn open_streams_during_inbound_traffic() {
+        const OPENERS: u64 = 8;
+        let (client, mut server) = connection_pair().await;
+
+        let responder = tokio::spawn(async move {
+            let mut handlers = JoinSet::new();
+            for _ in 0..STREAMS {
+                let Some(Incoming::Bidi(mut stream)) = server.incoming().await else {
+                    panic!("Expected an incoming bidi stream!");
+                };
+                handlers.spawn(async move {
+                    let mut request = [0; 8];
+                    stream.read_exact(&mut request).await.unwrap();
+                    stream.write_all(&payload(RESPONSE_LEN)).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+            while let Some(result) = handlers.join_next().await {
+                result.unwrap();
+            }
+            server
+        });
+
+        let client = Arc::new(tokio::sync::Mutex::new(client));
+        let mut openers = JoinSet::new();
+        for opener in 0..OPENERS {
+            let client = client.clone();
+            openers.spawn(async move {
+                let mut readers = JoinSet::new();
+                for id in (opener..STREAMS).step_by(OPENERS as usize) {
+                    let mut stream = client.lock().await.bidi(id).await.unwrap();
+                    stream.write_all(&id.to_be_bytes()).await.unwrap();
+                    readers.spawn(async move {
+                        let mut response = Vec::new();
+                        stream.read_to_end(&mut response).await.unwrap();
+                        response
+                    });
+                }
+                while let Some(response) = readers.join_next().await {
+                    assert_eq!(response.unwrap(), payload(RESPONSE_LEN));
+                }
+            });
         }
-        let (tx, rx) = mpsc::unbounded_channel();
-        let stream = UniStream::new(id, rx, self.message_send.clone());
-        map.insert(id, tx);
-        trace!("New uni stream: {}", stream.id);
-        Ok(stream)
+        while let Some(result) = openers.join_next().await {
+            result.unwrap();
+        }
+        // Stream 0 was never finished for writing, so its id stays taken.
+        assert!(matches!(
+            client.lock().await.bidi(0).await,
+            Err(Error::IdAlreadyTaken(0))
+        ));
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_a_stream_keeps_the_connection_usable() {
+        let (mut client, mut server) = connection_pair().await;
+
+        for id in 0..2 {
+            let mut stream = client.bidi(id).await.unwrap();
+            stream.write_all(&id.to_be_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+                panic!("Expected an incoming bidi stream!");
+            };
+            let mut received = Vec::new();
+            incoming.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, id.to_be_bytes());
+        }
+        assert!(client.stats().await.is_ok());
+    }
+
+    /// The server's connection-level flow control only leaves room for a fraction of the bulk
+    /// data, which it stops extending once it no longer reads. The urgent stream, opened after
+    /// the bulk one, only gets through if its data is handed to quiche first.
+    #[tokio::test]
+    async fn urgent_stream_gets_flow_control_credit_first() {
+        let mut config = config::default();
+        config.set_initial_max_data(16 * 1024);
+        let (mut client, mut server) = connection_pair_with_config(config, settings()).await;
+
+        let mut bulk = client.bidi(0).await.unwrap();
+        let mut urgent = client.bidi_with_priority(1, 0, false).await.unwrap();
+        // Queues both writes before the driver gets to run.
+        bulk.write_all(&payload(1024 * 1024)).await.unwrap();
+        urgent.write_all(b"urgent").await.unwrap();
+        urgent.shutdown().await.unwrap();
+
+        // The bulk stream is kept, but not read, so it holds up the flow control.
+        let mut streams = Vec::new();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let Some(Incoming::Bidi(mut stream)) = server.incoming().await else {
+                    panic!("Expected an incoming bidi stream!");
+                };
+                if stream.id() == urgent.id() {
+                    let mut received = Vec::new();
+                    stream.read_to_end(&mut received).await.unwrap();
+                    return received;
+                }
+                streams.push(stream);
+            }
+        })
+        .await
+        .expect("the urgent stream was starved by the bulk stream");
+        assert_eq!(received, b"urgent");
+    }
+
+    /// Stream data is held up by the server's flow control until too much of it is pending,
+    /// which must not keep other requests from being served.
+    #[tokio::test]
+    async fn control_messages_pass_held_up_data() {
+        let mut config = config::default();
+        config.set_initial_max_data(16 * 1024);
+        let limits = Limits {
+            connection_messages: 4,
+            pending_send_bytes: 64 * 1024,
+            ..Default::default()
+        };
+        let (mut client, _server) = connection_pair_with_config(
+            config,
+            Settings {
+                limits,
+                ..settings()
+            },
+        )
+        .await;
+
+        // The server never reads, so this write does not finish.
+        let mut bulk = client.bidi(0).await.unwrap();
+        tokio::spawn(async move { bulk.write_all(&payload(4 * 1024 * 1024)).await });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let served = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut urgent = client.bidi_with_priority(1, 0, false).await.unwrap();
+            let err = urgent.try_write(b"urgent").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+            urgent.set_priority(1, true).unwrap();
+            urgent.stats().await.unwrap();
+            client.stats().await.unwrap()
+        })
+        .await
+        .expect("requests were held up behind the stream data");
+        assert!(served.sent_bytes > 0);
+    }
+
+    #[tokio::test]
+    async fn selects_congestion_control_per_connection() {
+        let selected = Arc::new(AtomicUsize::new(0));
+        let counter = selected.clone();
+        let (mut client, mut server) = connection_pair_with(Settings {
+            congestion_control: Some(CongestionControl {
+                algorithm: CcAlgorithm::Reno,
+                hystart: false,
+            }),
+            select_congestion_control: Some(Arc::new(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                CongestionControl {
+                    algorithm: CcAlgorithm::Bbr2,
+                    ..Default::default()
+                }
+            })),
+            ..settings()
+        })
+        .await;
+        assert_eq!(selected.load(Ordering::Relaxed), 1);
+
+        let data = payload(100_000);
+        let mut stream = server.uni(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let Some(Incoming::Uni(mut incoming)) = client.incoming().await else {
+            panic!("Expected an incoming uni stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_outlives_idle_timeout() {
+        let (client, server) = connection_pair_with(Settings {
+            idle_timeout: Some(Duration::from_millis(300)),
+            keep_alive: Some(Duration::from_millis(100)),
+            ..settings()
+        })
+        .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(client.stats().await.is_ok());
+
+        // Without keep-alives the connection times out.
+        server.set_keep_alive(None).await.unwrap();
+        client.set_keep_alive(None).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(client.stats().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ping_measures_round_trip() {
+        let (client, _server) = connection_pair().await;
+        let rtt = client.ping().await.unwrap();
+        assert!(rtt < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn reports_handshake_info() {
+        let (client, server) = connection_pair().await;
+        let (client, server) = (client.handshake_info(), server.handshake_info());
+
+        assert_eq!(client.alpn, b"h3");
+        assert_eq!(server.alpn, b"h3");
+        assert_eq!(server.server_name.as_deref(), Some("localhost"));
+        assert_eq!(client.local_addr, server.peer_addr);
+        assert_eq!(client.peer_addr, server.local_addr);
+        assert!(!client.resumed);
+        assert_eq!(
+            client.peer_transport_params.max_idle_timeout,
+            Duration::from_secs(5)
+        );
+        assert_eq!(server.peer_transport_params.initial_max_streams_bidi, 100);
     }
 }
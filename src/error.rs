@@ -40,3 +40,20 @@ impl From<quiche::Error> for Error {
         Self::BackendError(value)
     }
 }
+
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::IoError(error) => error,
+            Error::BackendError(quiche::Error::StreamReset(code)) => std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                format!("Stream was reset by the peer with error code {code}."),
+            ),
+            Error::BackendError(quiche::Error::StreamStopped(code)) => std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("Stream was stopped by the peer with error code {code}."),
+            ),
+            error => std::io::Error::new(std::io::ErrorKind::Other, error),
+        }
+    }
+}
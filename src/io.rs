@@ -1,8 +1,14 @@
-use std::io::{ErrorKind, IoSlice, IoSliceMut, Write};
+use std::future::{poll_fn, Future};
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+use std::task::{ready, Context, Poll};
 use bytes::buf::BufMut;
+use bytes::{Buf, BytesMut};
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::Receiver;
+use crate::config::STREAM_BUFFER_SIZE;
+use crate::error::Result;
 use crate::Message;
-use crate::stream::BidiStream;
+use crate::stream::{buffer_message, BidiStream, Readable, StreamSender, UniStream, Writeable};
 
 /// The `TryRead` trait allows reading bytes from a source.
 /// In this case the source is a quic stream.
@@ -65,6 +71,34 @@ pub trait TryRead {
     /// If the stream is not ready to read data, or is already closed an error 
     /// will be returned.
     fn try_read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize>;
+
+    /// Polls for read readiness.
+    ///
+    /// For the streams of this crate, once this returns `Poll::Ready(Ok(()))` the next call to
+    /// [`try_read()`] will not fail with `WouldBlock`, it either returns data or `Ok(0)` if the
+    /// peer finished the stream. If the stream is not ready yet, the waker from `cx` is notified
+    /// once it is.
+    ///
+    /// The default implementation always reports readiness, so [`try_read()`] may still fail
+    /// with `WouldBlock` for implementations that do not override it.
+    ///
+    /// [`try_read()`]: TryRead::try_read()
+    fn poll_read_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Waits for the stream to become readable.
+    ///
+    /// This is usually paired with [`try_read()`] in a loop, retrying whenever it fails with
+    /// `WouldBlock`.
+    ///
+    /// [`try_read()`]: TryRead::try_read()
+    fn readable(&mut self) -> impl Future<Output = std::io::Result<()>> + Send + '_
+    where
+        Self: Send + Sized,
+    {
+        poll_fn(move |cx| self.poll_read_ready(cx))
+    }
 }
 
 /// The `TryWrite` trait allows writing bytes to a destination.
@@ -104,77 +138,361 @@ pub trait TryWrite {
     /// If the stream is not ready to write data, or is already closed an error 
     /// will be returned.
     fn try_write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize>;
+
+    /// Polls for write readiness.
+    ///
+    /// Returns `Poll::Ready(Ok(()))` once [`try_write()`] is able to accept data, or an error
+    /// if the stream can no longer be written to.
+    ///
+    /// The default implementation always reports readiness, so [`try_write()`] may still fail
+    /// with `WouldBlock` for implementations that do not override it.
+    ///
+    /// [`try_write()`]: TryWrite::try_write()
+    fn poll_write_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Waits for the stream to become writable.
+    ///
+    /// This is usually paired with [`try_write()`] in a loop, retrying whenever it fails with
+    /// `WouldBlock`.
+    ///
+    /// [`try_write()`]: TryWrite::try_write()
+    fn writable(&mut self) -> impl Future<Output = std::io::Result<()>> + Send + '_
+    where
+        Self: Send + Sized,
+    {
+        poll_fn(move |cx| self.poll_write_ready(cx))
+    }
 }
 
 impl TryRead for BidiStream {
-    fn try_read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut vec = Vec::with_capacity(buf.len());
-        self.try_read_buf(&mut vec)?;
-        buf.as_mut().write(vec.as_slice())
+    fn try_read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
+        self.try_read_buf(&mut buf)
     }
 
     fn try_read_buf<B: BufMut>(&mut self, buf: &mut B) -> std::io::Result<usize> {
-        let mut total_read = buf.remaining_mut().min(self.buffer_read.len());
-        buf.put_slice(&self.buffer_read[..buf.remaining_mut().min(self.buffer_read.len())]);
-        let remaining = self.buffer_read.len() - total_read;
-        self.buffer_read.truncate(remaining);
-        loop {
-            match self.rx.try_recv() {
-                Ok(message) => match message {
-                    Ok(message) => {
-                        if let Message::Data { stream_id: _, bytes, fin } = message {
-                            if fin {
-                                self.rx.close();
-                            }
-                            self.buffer_read.extend_from_slice(&bytes);
-                        }
-                    }
-                    Err(err) => {
-                        Err(std::io::Error::new(ErrorKind::Other, err.to_string()))?
-                    }
+        try_read_buffered(&mut self.rx, &mut self.buffer_read, &mut self.fin_recv, buf)
+    }
+
+    fn try_read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        try_read_vectored(self, bufs)
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        poll_read_ready(&mut self.rx, &mut self.buffer_read, &mut self.fin_recv, cx)
+    }
+}
+
+impl TryRead for UniStream<Readable> {
+    fn try_read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
+        self.try_read_buf(&mut buf)
+    }
+
+    fn try_read_buf<B: BufMut>(&mut self, buf: &mut B) -> std::io::Result<usize> {
+        try_read_buffered(&mut self.rx, &mut self.buffer, &mut self.fin_recv, buf)
+    }
+
+    fn try_read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        try_read_vectored(self, bufs)
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        poll_read_ready(&mut self.rx, &mut self.buffer, &mut self.fin_recv, cx)
+    }
+}
+
+impl TryWrite for BidiStream {
+    fn try_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        try_send(&mut self.tx, self.id, buf)
+    }
+
+    fn try_write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        try_write_vectored(self, bufs)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.tx.poll_reserve(cx)
+    }
+}
+
+impl TryWrite for UniStream<Writeable> {
+    fn try_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        try_send(&mut self.tx, self.id, buf)
+    }
+
+    fn try_write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        try_write_vectored(self, bufs)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.tx.poll_reserve(cx)
+    }
+}
+
+/// Non-blocking counterpart of the `AsyncRead` implementations.
+///
+/// Buffered data is handed out first, only then the driver's channel is checked for more.
+fn try_read_buffered<B: BufMut>(
+    rx: &mut Receiver<Result<Message>>,
+    buffer: &mut BytesMut,
+    fin_recv: &mut bool,
+    buf: &mut B,
+) -> std::io::Result<usize> {
+    loop {
+        if !buffer.is_empty() || !buf.has_remaining_mut() {
+            let read_amount = buf.remaining_mut().min(buffer.len());
+            buf.put_slice(&buffer[..read_amount]);
+            buffer.advance(read_amount);
+            return Ok(read_amount);
+        }
+        if *fin_recv {
+            return Ok(0);
+        }
+        match rx.try_recv() {
+            Ok(message) => buffer_message(Some(message), rx, buffer, fin_recv)?,
+            Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => buffer_message(None, rx, buffer, fin_recv)?,
+        }
+    }
+}
+
+/// Messages that leave the buffer empty without finishing the stream are skipped,
+/// otherwise the following `try_read` would fail with `WouldBlock`.
+fn poll_read_ready(
+    rx: &mut Receiver<Result<Message>>,
+    buffer: &mut BytesMut,
+    fin_recv: &mut bool,
+    cx: &mut Context<'_>,
+) -> Poll<std::io::Result<()>> {
+    while buffer.is_empty() && !*fin_recv {
+        let message = ready!(rx.poll_recv(cx));
+        buffer_message(message, rx, buffer, fin_recv)?;
+    }
+    Poll::Ready(Ok(()))
+}
+
+fn try_read_vectored<R: TryRead>(
+    reader: &mut R,
+    bufs: &mut [IoSliceMut<'_>],
+) -> std::io::Result<usize> {
+    let mut total_read = 0;
+    for buf in bufs {
+        match reader.try_read(buf) {
+            Ok(read) => {
+                total_read += read;
+                if read < buf.len() {
+                    break;
                 }
-                Err(TryRecvError::Empty) => {
-                    break
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock && total_read > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total_read)
+}
+
+/// Fails with `WouldBlock` while the connection's message queue is full.
+fn try_send(tx: &mut StreamSender, stream_id: u64, buf: &[u8]) -> std::io::Result<usize> {
+    let len = buf.len().min(STREAM_BUFFER_SIZE);
+    tx.try_send(Message::Data {
+        stream_id,
+        bytes: buf[..len].to_vec(),
+        fin: false,
+    })?;
+    Ok(len)
+}
+
+fn try_write_vectored<W: TryWrite>(writer: &mut W, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+    let mut total_written = 0;
+    for buf in bufs {
+        match writer.try_write(buf) {
+            Ok(written) => {
+                total_written += written;
+                if written < buf.len() {
+                    break;
                 }
-                Err(err) => {
-                    Err(std::io::Error::new(ErrorKind::Other, err.to_string()))?
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock && total_written > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total_written)
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::future::poll_fn;
+    use std::io::{ErrorKind, IoSlice, IoSliceMut};
+    use std::task::Poll;
+
+    use bytes::{BufMut, BytesMut};
+    use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    use crate::connection::Incoming;
+    use crate::io::{poll_read_ready, try_read_buffered, TryRead, TryWrite};
+    use crate::test_util::connection_pair;
+    use crate::Message;
+
+    async fn write_chunked<W: TryWrite + AsyncWrite + Unpin + Send>(
+        writer: &mut W,
+        data: &[u8],
+        rng: &mut StdRng,
+    ) {
+        let mut offset = 0;
+        while offset < data.len() {
+            let end = (offset + rng.gen_range(1..=4096)).min(data.len());
+            writer.writable().await.unwrap();
+            match writer.try_write(&data[offset..end]) {
+                Ok(written) => offset += written,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => panic!("try_write failed: {err}"),
+            }
+        }
+        writer.shutdown().await.unwrap();
+    }
+
+    /// Reads until EOF, switching randomly between the three `TryRead` methods.
+    async fn read_chunked<R: TryRead + Send>(reader: &mut R, rng: &mut StdRng) -> Vec<u8> {
+        let mut received = Vec::new();
+        loop {
+            reader.readable().await.unwrap();
+            let len = rng.gen_range(1..=4096);
+            let mut buf = vec![0; len];
+            let result = match rng.gen_range(0..3) {
+                0 => reader.try_read(&mut buf),
+                1 => {
+                    let mut bytes = BytesMut::with_capacity(len);
+                    let result = reader.try_read_buf(&mut (&mut bytes).limit(len));
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    result
                 }
+                _ => {
+                    let (first, second) = buf.split_at_mut(len / 2);
+                    let mut bufs = [IoSliceMut::new(first), IoSliceMut::new(second)];
+                    reader.try_read_vectored(&mut bufs)
+                }
+            };
+            match result {
+                Ok(0) => return received,
+                Ok(read) => received.extend_from_slice(&buf[..read]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => panic!("try_read failed: {err}"),
             }
         }
-        let to_write = buf.remaining_mut().min(self.buffer_read.len());
-        buf.put_slice(&self.buffer_read[..to_write]);
-        let remaining = self.buffer_read.len() - total_read;
-        self.buffer_read.truncate(remaining);
-        total_read += to_write;
-        Ok(total_read)
     }
 
-    fn try_read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
-        let mut total_read = 0;
-        for buf in bufs {
-            total_read += self.try_read(buf)?;
+    fn random_payload(rng: &mut StdRng) -> Vec<u8> {
+        let mut data = vec![0; rng.gen_range(1..=200_000)];
+        rng.fill_bytes(&mut data);
+        data
+    }
+
+    #[tokio::test]
+    async fn bidi_delivers_exact_bytes() {
+        for seed in 0..3 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let data = random_payload(&mut rng);
+            let (mut client, mut server) = connection_pair().await;
+
+            let mut stream = client.bidi(1).await.unwrap();
+            write_chunked(&mut stream, &data, &mut rng).await;
+
+            let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+                panic!("Expected an incoming bidi stream!");
+            };
+            assert_eq!(read_chunked(&mut incoming, &mut rng).await, data, "seed {seed}");
         }
-        Ok(total_read)
     }
-}
 
-impl TryWrite for BidiStream {
-    fn try_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self.tx.send(Message::Data {
-            stream_id: self.id,
-            bytes: buf.to_vec(),
-            fin: false,
-        }) {
-            Ok(()) => Ok(buf.len()),
-            Err(err) => Err(std::io::Error::new(ErrorKind::Other, err.to_string()))?
+    #[tokio::test]
+    async fn uni_delivers_exact_bytes() {
+        for seed in 0..3 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let data = random_payload(&mut rng);
+            let (mut client, mut server) = connection_pair().await;
+
+            let mut stream = client.uni(1).await.unwrap();
+            write_chunked(&mut stream, &data, &mut rng).await;
+
+            let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+                panic!("Expected an incoming uni stream!");
+            };
+            assert_eq!(read_chunked(&mut incoming, &mut rng).await, data, "seed {seed}");
         }
     }
 
-    fn try_write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
-        let mut total_written = 0;
-        for buf in bufs {
-            total_written += self.try_write(buf)?;
+    #[tokio::test]
+    async fn try_write_vectored_stops_after_short_write() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut first = vec![0; 100_000];
+        let mut second = vec![0; 10_000];
+        rng.fill_bytes(&mut first);
+        rng.fill_bytes(&mut second);
+        let (mut client, mut server) = connection_pair().await;
+
+        let mut stream = client.uni(1).await.unwrap();
+        let mut offset = 0;
+        while offset < first.len() + second.len() {
+            let bufs = if offset < first.len() {
+                [IoSlice::new(&first[offset..]), IoSlice::new(&second)]
+            } else {
+                [IoSlice::new(&second[offset - first.len()..]), IoSlice::new(&[])]
+            };
+            stream.writable().await.unwrap();
+            match stream.try_write_vectored(&bufs) {
+                Ok(written) => offset += written,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => panic!("try_write_vectored failed: {err}"),
+            }
         }
-        Ok(total_written)
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming uni stream!");
+        };
+        let received = read_chunked(&mut incoming, &mut rng).await;
+        assert_eq!(received, [first, second].concat());
+    }
+
+    #[tokio::test]
+    async fn try_read_would_block_without_data() {
+        let (mut client, mut server) = connection_pair().await;
+
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.try_write(b"ping").unwrap();
+
+        let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        incoming.readable().await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(incoming.try_read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        let err = incoming.try_read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn empty_message_is_not_ready() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let (mut buffer, mut fin_recv) = (BytesMut::new(), false);
+
+        let empty = Message::Data { stream_id: 1, bytes: Vec::new(), fin: false };
+        tx.try_send(Ok(empty)).unwrap();
+        let ready = poll_fn(|cx| {
+            Poll::Ready(poll_read_ready(&mut rx, &mut buffer, &mut fin_recv, cx).is_ready())
+        })
+        .await;
+        assert!(!ready);
+
+        let data = Message::Data { stream_id: 1, bytes: b"ping".to_vec(), fin: false };
+        tx.try_send(Ok(data)).unwrap();
+        poll_fn(|cx| poll_read_ready(&mut rx, &mut buffer, &mut fin_recv, cx)).await.unwrap();
+        let mut buf = [0; 16];
+        let read = try_read_buffered(&mut rx, &mut buffer, &mut fin_recv, &mut &mut buf[..]).unwrap();
+        assert_eq!(&buf[..read], b"ping");
+    }
+}
@@ -38,41 +38,60 @@
 //! }
 //! ```
 
-use log::trace;
-use std::sync::Arc;
+use std::{collections::VecDeque, net::SocketAddr, sync::Arc, time::Duration};
 
-use crate::backend::Handshaker;
+use crate::backend::{connection_span, setup_connection, Handshaker};
 use backend::{
     client,
     manager::{self, Manager},
     server,
     timer::Timer,
+    udp::{self, RecvBatch, SendBatch},
 };
-use config::{MAX_DATAGRAM_SIZE, STREAM_BUFFER_SIZE};
+use config::{CongestionControl, Role, Settings, MAX_DATAGRAM_SIZE};
 use connection::{QuicConnection, ToClient, ToServer};
 use error::Result;
 use quiche::ConnectionId;
 use rand::Rng;
 use ring::rand::SystemRandom;
+use stats::{ConnectionStats, EcnCounts, StreamStats};
+use tls::{Reload, TlsReloader};
 use tokio::{
-    net::{ToSocketAddrs, UdpSocket},
-    sync::mpsc::{self, UnboundedReceiver},
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver},
+        oneshot, watch,
+    },
     task::JoinHandle,
 };
+use trace::{event, span};
+use transport::DatagramTransport;
 
+mod async_io;
 mod backend;
 pub mod config;
 pub mod connection;
 mod crypto;
 pub mod error;
-pub mod stream;
 mod io;
-mod async_io;
+pub mod metrics;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod stats;
+pub mod stream;
+#[cfg(all(test, feature = "key-gen"))]
+mod test_util;
+pub mod tls;
+mod trace;
+pub mod transport;
 
 pub use io::{TryRead, TryWrite};
 
 #[derive(Debug)]
 /// Passed between the backend and a stream for exchange of data.
+///
+/// `Data` and `Close` go through the data channel of a connection, which is not read from while
+/// too much stream data is pending. All other messages go through its control channel.
 pub(crate) enum Message {
     Data {
         stream_id: u64,
@@ -81,6 +100,31 @@ pub(crate) enum Message {
     },
     /// Contains the id of the stream to be closed
     Close(u64),
+    /// Registers a locally opened stream, fails if its id is already in use.
+    /// The stream is given `priority` as urgency and incremental flag, see `Priority`.
+    Open {
+        stream_id: u64,
+        tx: mpsc::Sender<Result<Message>>,
+        priority: Option<(u8, bool)>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Changes the priority of a stream, see `quiche::Connection::stream_priority`.
+    Priority {
+        stream_id: u64,
+        urgency: u8,
+        incremental: bool,
+    },
+    /// Requests a statistics snapshot of the connection.
+    Stats(oneshot::Sender<ConnectionStats>),
+    /// Sends an ack-eliciting packet, the reply is sent once it was acknowledged.
+    Ping(oneshot::Sender<Duration>),
+    /// Changes the keep-alive interval, see `Settings::keep_alive`.
+    KeepAlive(Option<Duration>),
+    /// Requests the counters of a single stream.
+    StreamStats {
+        stream_id: u64,
+        reply: oneshot::Sender<StreamStats>,
+    },
 }
 
 /// `QuicListener` is used to bind to a specified address/port.
@@ -91,10 +135,15 @@ pub(crate) enum Message {
 /// If the feature `key-gen` is enabled this config will already come with a certificate and private key,
 /// although these are just for testing and are not recommended to be used in production.
 pub struct QuicListener {
-    io: Arc<UdpSocket>,
+    io: Arc<dyn DatagramTransport>,
     #[allow(unused)]
     handle: JoinHandle<Result<()>>,
-    connection_recv: UnboundedReceiver<manager::Client>,
+    connection_recv: Receiver<manager::Client>,
+    settings: Settings,
+    reloader: TlsReloader,
+    /// Connections set aside by `accept_alpn` for another protocol.
+    accepted: VecDeque<QuicConnection<ToClient>>,
+    span: trace::Span,
 }
 
 impl QuicListener {
@@ -124,53 +173,170 @@ impl QuicListener {
         config: quiche::Config,
         secret: Vec<u8>,
     ) -> Result<Self> {
-        trace!("Bind listener [{secret:?}]");
-        let io = Arc::new(UdpSocket::bind(addr).await?);
+        Self::bind_with_settings(addr, config, secret, Settings::default()).await
+    }
+
+    /// Bind to a specified address with a `quiche::Config` and additional `Settings`.
+    ///
+    /// The socket is created with `Settings::socket` applied.
+    pub async fn bind_with_settings<A: ToSocketAddrs>(
+        addr: A,
+        config: quiche::Config,
+        secret: Vec<u8>,
+        settings: Settings,
+    ) -> Result<Self> {
+        let io = udp::bind(addr, &settings.socket).await?;
+        Self::from_socket(io, config, secret, settings)
+    }
+
+    /// Listens on a socket that was bound elsewhere, e.g. passed in by systemd socket activation.
+    ///
+    /// The socket is used as it is, `Settings::socket` is ignored.
+    /// Has to be called from within a tokio runtime.
+    pub fn from_socket(
+        io: UdpSocket,
+        config: quiche::Config,
+        secret: Vec<u8>,
+        settings: Settings,
+    ) -> Result<Self> {
+        Self::with_transport(Arc::new(io), config, secret, settings)
+    }
+
+    /// Listens on a custom `DatagramTransport` instead of a UDP socket.
+    ///
+    /// `Settings::socket` is ignored.
+    /// Has to be called from within a tokio runtime.
+    pub fn with_transport(
+        io: Arc<dyn DatagramTransport>,
+        mut config: quiche::Config,
+        secret: Vec<u8>,
+        mut settings: Settings,
+    ) -> Result<Self> {
+        settings.apply(&mut config)?;
+        udp::configure(&*io, &mut settings);
+        let span = span!(info, "listener", local_addr = io.local_addr()?);
         let rng = SystemRandom::new();
-        let (tx, connection_recv) = mpsc::unbounded_channel();
+        let (tx, connection_recv) = mpsc::channel(settings.limits.accept_queue);
+        let (config_send, config_recv) = watch::channel(Reload::default());
         let manager = Manager::new(
             io.clone(),
             ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap(),
             secret,
             config,
+            config_recv,
             tx,
+            &settings,
         );
-        let handle = tokio::spawn(manager);
+        let handle = tokio::spawn(trace::instrument(manager, span.clone()));
         Ok(Self {
             io,
             handle,
             connection_recv,
+            reloader: TlsReloader::new(config_send, settings.clone()),
+            accepted: VecDeque::new(),
+            settings,
+            span,
         })
     }
 
+    /// Like `from_socket`, but takes a socket from the standard library,
+    /// which is switched to non-blocking mode.
+    pub fn from_std(
+        io: std::net::UdpSocket,
+        config: quiche::Config,
+        secret: Vec<u8>,
+        settings: Settings,
+    ) -> Result<Self> {
+        io.set_nonblocking(true)?;
+        Self::from_socket(UdpSocket::from_std(io)?, config, secret, settings)
+    }
+
+    /// Returns the local address that this listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.io.local_addr()?)
+    }
+
+    /// Replaces the config used for new handshakes, e.g. to rotate the certificate.
+    ///
+    /// The config replaces the one passed at bind time as a whole, so apart from the certificate
+    /// it should be built like that one. `Settings` are applied to it again.
+    /// Connections that were accepted already keep using the previous config.
+    /// Returns once new handshakes use the config.
+    pub async fn reload_tls(&self, config: quiche::Config) -> Result<()> {
+        self.reloader.reload(config).await
+    }
+
+    /// Returns a handle that can reload the config from other tasks, see `reload_tls`.
+    pub fn tls_reloader(&self) -> TlsReloader {
+        self.reloader.clone()
+    }
+
     /// Accepts an incoming connection.
+    ///
+    /// Connections set aside by `accept_alpn` are returned first.
     pub async fn accept(&mut self) -> Result<QuicConnection<ToClient>> {
-        let manager::Client { connection, recv } = self.connection_recv.recv().await.unwrap();
+        match self.accepted.pop_front() {
+            Some(connection) => Ok(connection),
+            None => self.accept_new().await,
+        }
+    }
+
+    /// Accepts an incoming connection that negotiated the application protocol `alpn`,
+    /// so one port can serve several protocols from different tasks.
+    ///
+    /// Connections for other protocols are set aside for later calls.
+    /// At most `Limits::accept_queue` are kept, further ones are dropped.
+    pub async fn accept_alpn(&mut self, alpn: &[u8]) -> Result<QuicConnection<ToClient>> {
+        if let Some(index) = self
+            .accepted
+            .iter()
+            .position(|connection| connection.handshake_info().alpn == alpn)
+        {
+            return Ok(self.accepted.remove(index).unwrap());
+        }
+        loop {
+            let connection = self.accept_new().await?;
+            if connection.handshake_info().alpn == alpn {
+                return Ok(connection);
+            }
+            if self.accepted.len() < self.settings.limits.accept_queue {
+                self.accepted.push_back(connection);
+            } else {
+                event!(
+                    debug,
+                    "Dropped connection",
+                    reason = "accept queue full",
+                    from = connection.handshake_info().peer_addr
+                );
+            }
+        }
+    }
+
+    async fn accept_new(&mut self) -> Result<QuicConnection<ToClient>> {
+        let manager::Client {
+            connection,
+            recv,
+            from,
+        } = self.connection_recv.recv().await.unwrap();
 
         let mut inner = server::Inner {
             io: self.io.clone(),
             connection,
             data_recv: recv,
-            send_flush: false,
-            send_end: 0,
-            send_pos: 0,
-            recv_buf: vec![0; STREAM_BUFFER_SIZE],
-            send_buf: vec![0; MAX_DATAGRAM_SIZE],
-            timer: Timer::Unset,
-            last_address: None,
+            send_batch: SendBatch::new(&*self.io, self.settings.pacing.unwrap_or_default()),
+            ecn: EcnCounts::default(),
+            timer: Timer::new(),
         };
-        trace!(
-            "Accepted connection trace-id: {:?}, server-name: {:?}",
-            inner.connection.trace_id(),
-            inner.connection.server_name()
-        );
-        Handshaker(&mut inner).await?;
-        trace!(
-            "Handshake complete trace-id: {:?}, server-name: {:?}",
-            inner.connection.trace_id(),
-            inner.connection.server_name()
-        );
-        Ok(QuicConnection::<ToClient>::new(inner))
+        setup_connection(&mut inner.connection, &self.settings, Role::Server);
+        let span = connection_span(&inner.connection, from, Role::Server, &self.span);
+        trace::instrument(Handshaker(&mut inner), span.clone()).await?;
+        let addrs = (self.io.local_addr()?, from);
+        Ok(QuicConnection::<ToClient>::new(
+            inner,
+            addrs,
+            span,
+            &self.settings,
+        ))
     }
 }
 
@@ -182,8 +348,9 @@ impl QuicListener {
 /// If the feature `key-gen` is enabled this config will already come with a certificate and private key,
 /// although these are just for testing and are not recommended to be used in production.
 pub struct QuicSocket {
-    io: Arc<UdpSocket>,
+    io: Arc<dyn DatagramTransport>,
     config: quiche::Config,
+    settings: Settings,
 }
 
 impl QuicSocket {
@@ -207,47 +374,164 @@ impl QuicSocket {
         addr: A,
         config: quiche::Config,
     ) -> Result<Self> {
+        Self::bind_with_settings(addr, config, Settings::default()).await
+    }
+
+    /// Bind to a specified address with a `quiche::Config` and additional `Settings`.
+    ///
+    /// The socket is created with `Settings::socket` applied.
+    pub async fn bind_with_settings<A: ToSocketAddrs>(
+        addr: A,
+        config: quiche::Config,
+        settings: Settings,
+    ) -> Result<Self> {
+        let io = udp::bind(addr, &settings.socket).await?;
+        Self::from_socket(io, config, settings)
+    }
+
+    /// Connects from a socket that was bound elsewhere.
+    ///
+    /// The socket is used as it is, `Settings::socket` is ignored.
+    pub fn from_socket(io: UdpSocket, config: quiche::Config, settings: Settings) -> Result<Self> {
+        Self::with_transport(Arc::new(io), config, settings)
+    }
+
+    /// Connects over a custom `DatagramTransport` instead of a UDP socket.
+    ///
+    /// `Settings::socket` is ignored.
+    pub fn with_transport(
+        io: Arc<dyn DatagramTransport>,
+        mut config: quiche::Config,
+        mut settings: Settings,
+    ) -> Result<Self> {
+        settings.apply(&mut config)?;
+        udp::configure(&*io, &mut settings);
         Ok(Self {
-            io: Arc::new(UdpSocket::bind(addr).await?),
+            io,
             config,
+            settings,
         })
     }
 
+    /// Like `from_socket`, but takes a socket from the standard library,
+    /// which is switched to non-blocking mode.
+    /// Has to be called from within a tokio runtime.
+    pub fn from_std(
+        io: std::net::UdpSocket,
+        config: quiche::Config,
+        settings: Settings,
+    ) -> Result<Self> {
+        io.set_nonblocking(true)?;
+        Self::from_socket(UdpSocket::from_std(io)?, config, settings)
+    }
+
+    /// Changes the congestion controller of the connections opened from now on.
+    pub fn set_congestion_control(&mut self, congestion_control: CongestionControl) {
+        congestion_control.apply(&mut self.config);
+        self.settings.congestion_control = Some(congestion_control);
+    }
+
+    /// Changes the idle timeout of the connections opened from now on.
+    ///
+    /// See `Settings::idle_timeout`.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.config
+            .set_max_idle_timeout(idle_timeout.as_millis() as u64);
+        self.settings.idle_timeout = Some(idle_timeout);
+    }
+
     /// Connect to a remote server.
     ///
     /// `server_name` needs to have a value in order to validate the server's certificate.
     /// Can be set to `None`, if validation is turned off.
+    ///
+    /// The first resolved address of the local address' family is used. A socket bound to the
+    /// unspecified IPv6 address falls back to an IPv4 address, which it reaches IPv4-mapped.
     pub async fn connect<A: ToSocketAddrs>(
         &mut self,
         server_name: Option<&str>,
         addr: A,
     ) -> Result<QuicConnection<ToServer>> {
-        self.io.connect(addr).await?;
+        let local = self.io.local_addr()?;
+        let peer = select_peer(local, lookup_host(addr).await?)
+            .ok_or(std::io::ErrorKind::AddrNotAvailable)?;
         let mut scid = vec![0; 16];
         rand::thread_rng().fill(&mut *scid);
         let scid: ConnectionId = scid.into();
-        let connection = quiche::connect(
-            server_name,
-            &scid,
-            self.io.local_addr()?,
-            self.io.peer_addr()?,
-            &mut self.config,
-        )
-        .unwrap();
+        let connection =
+            quiche::connect(server_name, &scid, local, peer, &mut self.config).unwrap();
 
         let mut inner = client::Inner {
             io: self.io.clone(),
+            peer,
             connection,
-            send_flush: false,
-            send_end: 0,
-            send_pos: 0,
-            recv_buf: vec![0; STREAM_BUFFER_SIZE],
-            send_buf: vec![0; MAX_DATAGRAM_SIZE],
-            timer: Timer::Unset,
+            send_batch: SendBatch::new(&*self.io, self.settings.pacing.unwrap_or_default()),
+            recv_batch: RecvBatch::new(),
+            ecn: EcnCounts::default(),
+            timer: Timer::new(),
         };
+        setup_connection(&mut inner.connection, &self.settings, Role::Client);
+        let span = connection_span(
+            &inner.connection,
+            peer,
+            Role::Client,
+            &trace::Span::current(),
+        );
+
+        trace::instrument(Handshaker(&mut inner), span.clone()).await?;
+
+        Ok(QuicConnection::<ToServer>::new(
+            inner,
+            (local, peer),
+            span,
+            &self.settings,
+        ))
+    }
+}
+
+/// Picks the address of `addrs` that a socket bound to `local` can send to.
+///
+/// A dual-stack socket receives datagrams of IPv4 peers from their IPv4-mapped address,
+/// so that is the address such a peer has to be known by.
+fn select_peer(
+    local: SocketAddr,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> Option<SocketAddr> {
+    let mut fallback = None;
+    for addr in addrs {
+        if addr.is_ipv4() == local.is_ipv4() {
+            return Some(addr);
+        }
+        if let (SocketAddr::V4(v4), true) = (addr, local.ip().is_unspecified()) {
+            fallback.get_or_insert(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()));
+        }
+    }
+    fallback
+}
 
-        Handshaker(&mut inner).await?;
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
 
-        Ok(QuicConnection::<ToServer>::new(inner))
+    use super::select_peer;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn selects_peer_of_local_family() {
+        let peers = [addr("127.0.0.1:443"), addr("[::1]:443")];
+        assert_eq!(
+            select_peer(addr("0.0.0.0:0"), peers),
+            Some(addr("127.0.0.1:443"))
+        );
+        assert_eq!(select_peer(addr("[::]:0"), peers), Some(addr("[::1]:443")));
+        assert_eq!(
+            select_peer(addr("[::]:0"), [addr("127.0.0.1:443")]),
+            Some(addr("[::ffff:127.0.0.1]:443"))
+        );
+        assert_eq!(select_peer(addr("[::1]:0"), [addr("127.0.0.1:443")]), None);
+        assert_eq!(select_peer(addr("127.0.0.1:0"), [addr("[::1]:443")]), None);
     }
 }
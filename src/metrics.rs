@@ -0,0 +1,218 @@
+//! Hooks for collecting counters about the listener and its connections.
+//!
+//! Implement [`Metrics`] and pass it in `Settings::metrics` to be notified about every
+//! decision the listener makes about an incoming packet, e.g. to alert on retry storms.
+//! With the `metrics` feature enabled [`GlobalRecorder`] reports everything to the
+//! recorder installed for the [`metrics`](https://docs.rs/metrics) crate.
+
+use std::fmt::{Display, Formatter};
+
+/// Why the listener dropped a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The QUIC header could not be parsed.
+    InvalidHeader,
+    /// The packet is not an Initial and belongs to no known connection.
+    UnknownConnectionId,
+    /// The address validation token of an Initial was invalid or expired.
+    InvalidToken,
+    /// The destination connection id of an Initial had the wrong length.
+    InvalidConnectionId,
+    /// The queue of `QuicListener::accept` was full, see `Limits::accept_queue`.
+    AcceptQueueFull,
+    /// The connection's packet queue was full, see `Limits::connection_packets`.
+    ConnectionQueueFull,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::InvalidHeader => "invalid_header",
+            DropReason::UnknownConnectionId => "unknown_connection_id",
+            DropReason::InvalidToken => "invalid_token",
+            DropReason::InvalidConnectionId => "invalid_connection_id",
+            DropReason::AcceptQueueFull => "accept_queue_full",
+            DropReason::ConnectionQueueFull => "connection_queue_full",
+        }
+    }
+}
+
+impl Display for DropReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Internal channel that a message could not be sent on, because its receiver is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// From the listener to `QuicListener::accept`.
+    Accept,
+    /// From the listener to the task driving a connection.
+    Packet,
+    /// From a connection to the `QuicConnection` announcing a new incoming stream.
+    Incoming,
+    /// From a connection to one of its streams.
+    Stream,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Accept => "accept",
+            Channel::Packet => "packet",
+            Channel::Incoming => "incoming",
+            Channel::Stream => "stream",
+        }
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Receives an event for every decision the listener and the connection drivers make.
+///
+/// All methods do nothing by default, so an implementation only needs to override the
+/// events it is interested in. They are called from within the I/O tasks and should return quickly.
+pub trait Metrics: Send + Sync {
+    /// An Initial packet for a new connection was received.
+    fn initial_received(&self) {}
+
+    /// A version negotiation packet was sent in response to an unsupported version.
+    fn version_negotiation_sent(&self) {}
+
+    /// A Retry packet was sent to validate the address of a new client.
+    fn retry_sent(&self) {}
+
+    /// A packet was dropped by the listener.
+    fn packet_dropped(&self, _reason: DropReason) {}
+
+    /// A new connection was accepted and handed over to `QuicListener::accept`.
+    fn connection_accepted(&self) {}
+
+    /// A connection was closed, `timed_out` is set if it ran into its idle timeout.
+    fn connection_closed(&self, _timed_out: bool) {}
+
+    /// A message was dropped, because the receiving end of an internal channel is gone.
+    fn channel_send_failed(&self, _channel: Channel) {}
+}
+
+/// Ignores all events, this is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Reports every event as a counter to the recorder installed for the `metrics` crate.
+///
+/// | Counter                                | Labels      |
+/// |----------------------------------------|-------------|
+/// | `quic_initials_received_total`         |             |
+/// | `quic_version_negotiations_sent_total` |             |
+/// | `quic_retries_sent_total`              |             |
+/// | `quic_packets_dropped_total`           | `reason`    |
+/// | `quic_connections_accepted_total`      |             |
+/// | `quic_connections_closed_total`        | `timed_out` |
+/// | `quic_channel_send_failures_total`     | `channel`   |
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalRecorder;
+
+#[cfg(feature = "metrics")]
+impl Metrics for GlobalRecorder {
+    fn initial_received(&self) {
+        ::metrics::counter!("quic_initials_received_total").increment(1);
+    }
+
+    fn version_negotiation_sent(&self) {
+        ::metrics::counter!("quic_version_negotiations_sent_total").increment(1);
+    }
+
+    fn retry_sent(&self) {
+        ::metrics::counter!("quic_retries_sent_total").increment(1);
+    }
+
+    fn packet_dropped(&self, reason: DropReason) {
+        ::metrics::counter!("quic_packets_dropped_total", "reason" => reason.as_str()).increment(1);
+    }
+
+    fn connection_accepted(&self) {
+        ::metrics::counter!("quic_connections_accepted_total").increment(1);
+    }
+
+    fn connection_closed(&self, timed_out: bool) {
+        let timed_out = if timed_out { "true" } else { "false" };
+        ::metrics::counter!("quic_connections_closed_total", "timed_out" => timed_out).increment(1);
+    }
+
+    fn channel_send_failed(&self, channel: Channel) {
+        ::metrics::counter!("quic_channel_send_failures_total", "channel" => channel.as_str())
+            .increment(1);
+    }
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::Metrics;
+    use crate::config::{self, Settings};
+    use crate::test_util::settings;
+    use crate::{QuicListener, QuicSocket};
+
+    #[derive(Default)]
+    struct Counting {
+        initials: AtomicUsize,
+        retries: AtomicUsize,
+        accepted: AtomicUsize,
+    }
+
+    impl Metrics for Counting {
+        fn initial_received(&self) {
+            self.initials.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn retry_sent(&self) {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn connection_accepted(&self) {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_handshake() {
+        let metrics = Arc::new(Counting::default());
+        let server_settings = Settings {
+            metrics: metrics.clone(),
+            ..settings()
+        };
+        let mut listener = QuicListener::bind_with_settings(
+            "127.0.0.1:0",
+            config::default(),
+            vec![7; 16],
+            server_settings,
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut socket =
+            QuicSocket::bind_with_settings("127.0.0.1:0", config::default(), settings())
+                .await
+                .unwrap();
+        let (client, server) =
+            tokio::join!(socket.connect(Some("localhost"), addr), listener.accept());
+        client.unwrap();
+        server.unwrap();
+
+        // The first Initial is answered with a Retry, the second one carries the token.
+        assert_eq!(metrics.retries.load(Ordering::Relaxed), 1);
+        assert!(metrics.initials.load(Ordering::Relaxed) >= 2);
+        assert_eq!(metrics.accepted.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,460 @@
+//! A simulated network to test listeners and sockets under controlled conditions.
+//!
+//! Every endpoint is a [`SimTransport`] bound to an address of a [`Network`], which passes
+//! datagrams between them according to the [`LinkConfig`] of each direction: with loss,
+//! delay and jitter, reordering, duplication, a bandwidth cap with a bounded queue and an MTU.
+//!
+//! All delays are measured with `tokio::time`, so the simulation is meant to run on a paused
+//! clock, e.g. in a `#[tokio::test(start_paused = true)]`, where time only advances while all
+//! tasks wait. `Network::new`'s `seed` only drives the link's decisions to drop, delay, reorder
+//! and duplicate datagrams. It does not make a run repeatable.
+//!
+//! quiche itself reads the system clock, for example to estimate the round-trip time and to
+//! decide when its timers expire, and offers no way to replace it. The delays of a link therefore
+//! shape the order datagrams arrive in, but are not reflected in quiche's recovery statistics.
+//! A timeout such as loss detection only fires once the system clock reached it, while the
+//! paused clock keeps jumping ahead to it, so virtual time runs ahead of quiche meanwhile.
+//! The scheduling of tasks and the system clock both vary between runs.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::ReadBuf;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+use crate::transport::DatagramTransport;
+
+/// Size of the IP and UDP headers that count towards the MTU.
+const IPV4_OVERHEAD: usize = 20 + 8;
+const IPV6_OVERHEAD: usize = 40 + 8;
+
+/// How datagrams travel in one direction between two endpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConfig {
+    /// One-way delay every datagram is held back for.
+    pub delay: Duration,
+    /// Upper bound of a random delay that is added on top of `delay`,
+    /// datagrams overtake each other if it exceeds the time between them.
+    pub jitter: Duration,
+    /// Probability with which a datagram is lost.
+    pub loss: f64,
+    /// Probability with which a datagram is held back for another `delay`,
+    /// so the ones sent after it overtake it.
+    pub reorder: f64,
+    /// Probability with which a datagram is delivered twice, each copy with its own jitter.
+    pub duplicate: f64,
+    /// Bytes per second the link transmits, `None` for no limit.
+    pub bandwidth: Option<u64>,
+    /// Bytes waiting for transmission on a link with a `bandwidth`,
+    /// datagrams that would exceed this are dropped.
+    pub queue: usize,
+    /// Largest IP packet the link carries, larger datagrams are dropped.
+    pub mtu: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            reorder: 0.0,
+            duplicate: 0.0,
+            bandwidth: None,
+            queue: 64 * 1024,
+            mtu: 1500,
+        }
+    }
+}
+
+/// What happened to the datagrams sent through a `Network`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Datagrams handed to the network.
+    pub sent: u64,
+    /// Copies of datagrams that reached an endpoint, including duplicates.
+    pub delivered: u64,
+    /// Datagrams that were lost at random.
+    pub lost: u64,
+    /// Datagrams that were dropped, because the link's queue was full.
+    pub queue_dropped: u64,
+    /// Datagrams that were dropped, because they exceeded the link's MTU.
+    pub too_large: u64,
+    /// Datagrams to an address that no endpoint is bound to.
+    pub unroutable: u64,
+    /// Datagrams that were held back to be reordered.
+    pub reordered: u64,
+    /// Datagrams that were delivered twice.
+    pub duplicated: u64,
+}
+
+/// A network of simulated endpoints, cheap to clone.
+#[derive(Clone)]
+pub struct Network {
+    seed: u64,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    rng: StdRng,
+    default_link: LinkConfig,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    stats: NetworkStats,
+    /// Keeps datagrams that are due at the same time in the order they were sent.
+    sequence: u64,
+}
+
+struct Link {
+    config: LinkConfig,
+    /// When the link has transmitted everything queued so far, only with a `bandwidth`.
+    busy_until: Instant,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    /// Datagrams in flight to the endpoint, ordered by their arrival.
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    /// The task waiting for a datagram.
+    waker: Option<Waker>,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    arrival: Instant,
+    sequence: u64,
+    from: SocketAddr,
+    datagram: Vec<u8>,
+}
+
+impl Network {
+    /// Creates a network where every direction between two endpoints behaves like `link`,
+    /// unless configured otherwise with `set_link`.
+    pub fn new(seed: u64, link: LinkConfig) -> Self {
+        Self {
+            seed,
+            state: Arc::new(Mutex::new(State {
+                rng: StdRng::seed_from_u64(seed),
+                default_link: link,
+                links: HashMap::new(),
+                endpoints: HashMap::new(),
+                stats: NetworkStats::default(),
+                sequence: 0,
+            })),
+        }
+    }
+
+    /// The seed the network was created with, e.g. to report it when a scenario fails.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Changes how datagrams travel from `from` to `to`, the other direction is not affected.
+    ///
+    /// Datagrams already in flight keep their arrival time.
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, config: LinkConfig) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .links
+            .entry((from, to))
+            .and_modify(|link| link.config = config)
+            .or_insert(Link {
+                config,
+                busy_until: Instant::now(),
+            });
+    }
+
+    /// Creates an endpoint with the address `addr`.
+    ///
+    /// Has to be called from within a tokio runtime.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<SimTransport> {
+        let mut state = self.state.lock().unwrap();
+        if state.endpoints.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        state.endpoints.insert(addr, Endpoint::default());
+        Ok(SimTransport {
+            addr,
+            state: self.state.clone(),
+            sleep: Mutex::new(Box::pin(sleep_until(Instant::now()))),
+        })
+    }
+
+    /// What happened to the datagrams sent so far.
+    pub fn stats(&self) -> NetworkStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+impl State {
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, datagram: &[u8]) {
+        self.stats.sent += 1;
+        if !self.endpoints.contains_key(&to) {
+            self.stats.unroutable += 1;
+            return;
+        }
+        let now = Instant::now();
+        let default_link = self.default_link;
+        let link = self.links.entry((from, to)).or_insert(Link {
+            config: default_link,
+            busy_until: now,
+        });
+        let config = link.config;
+
+        let overhead = if to.is_ipv4() {
+            IPV4_OVERHEAD
+        } else {
+            IPV6_OVERHEAD
+        };
+        if datagram.len() + overhead > config.mtu {
+            self.stats.too_large += 1;
+            return;
+        }
+        if self.rng.gen_bool(config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let mut departure = now;
+        if let Some(bandwidth) = config.bandwidth {
+            let start = link.busy_until.max(now);
+            let queued = (start - now).as_secs_f64() * bandwidth as f64;
+            if queued + datagram.len() as f64 > config.queue as f64 {
+                self.stats.queue_dropped += 1;
+                return;
+            }
+            departure = start + Duration::from_secs_f64(datagram.len() as f64 / bandwidth as f64);
+            link.busy_until = departure;
+        }
+
+        let mut arrival = departure + config.delay;
+        if self.rng.gen_bool(config.reorder) {
+            self.stats.reordered += 1;
+            arrival += config.delay;
+        }
+        let copies = if self.rng.gen_bool(config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let jitter = config.jitter.mul_f64(self.rng.gen::<f64>());
+            self.sequence += 1;
+            let endpoint = self.endpoints.get_mut(&to).unwrap();
+            endpoint.in_flight.push(Reverse(InFlight {
+                arrival: arrival + jitter,
+                sequence: self.sequence,
+                from,
+                datagram: datagram.to_vec(),
+            }));
+            if let Some(waker) = endpoint.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// An endpoint of a `Network`, pass it to `QuicListener::with_transport`
+/// or `QuicSocket::with_transport`.
+pub struct SimTransport {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    /// Wakes the receiving task when the next datagram arrives.
+    sleep: Mutex<Pin<Box<Sleep>>>,
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().endpoints.remove(&self.addr);
+    }
+}
+
+impl DatagramTransport for SimTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn poll_send_to(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        to: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(self.try_send_to(buf, to))
+    }
+
+    fn try_send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+        self.state.lock().unwrap().send(self.addr, to, buf);
+        Ok(())
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        let mut state = self.state.lock().unwrap();
+        let endpoint = state.endpoints.get_mut(&self.addr).unwrap();
+        let mut sleep = self.sleep.lock().unwrap();
+        loop {
+            let Some(Reverse(next)) = endpoint.in_flight.peek() else {
+                endpoint.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+            if next.arrival > Instant::now() {
+                let arrival = next.arrival;
+                if sleep.deadline() != arrival {
+                    sleep.as_mut().reset(arrival);
+                }
+                if sleep.as_mut().poll(cx).is_pending() {
+                    // A datagram sent meanwhile may arrive earlier.
+                    endpoint.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                continue;
+            }
+            let Reverse(next) = endpoint.in_flight.pop().unwrap();
+            state.stats.delivered += 1;
+            // Like UDP, whatever does not fit into the buffer is cut off.
+            let len = next.datagram.len().min(buf.remaining());
+            buf.put_slice(&next.datagram[..len]);
+            return Poll::Ready(Ok(next.from));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::future::poll_fn;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+    use super::{LinkConfig, Network, NetworkStats};
+    use crate::config;
+    use crate::connection::{Incoming, QuicConnection, ToClient, ToServer};
+    use crate::test_util::{payload, settings};
+    use crate::transport::DatagramTransport;
+    use crate::{QuicListener, QuicSocket};
+
+    /// Connects a client at 10.0.0.1 to a server at 10.0.0.2 of `network`.
+    async fn connection_pair(
+        network: &Network,
+    ) -> (QuicConnection<ToServer>, QuicConnection<ToClient>) {
+        let client_addr = "10.0.0.1:5000".parse().unwrap();
+        let server_addr = "10.0.0.2:4433".parse().unwrap();
+        let mut listener = QuicListener::with_transport(
+            Arc::new(network.bind(server_addr).unwrap()),
+            config::default(),
+            vec![7; 16],
+            settings(),
+        )
+        .unwrap();
+        let mut socket = QuicSocket::with_transport(
+            Arc::new(network.bind(client_addr).unwrap()),
+            config::default(),
+            settings(),
+        )
+        .unwrap();
+        let (client, server) = tokio::join!(
+            socket.connect(Some("localhost"), server_addr),
+            listener.accept()
+        );
+        (client.unwrap(), server.unwrap())
+    }
+
+    /// Transfers `len` bytes from the client to the server and returns the network's stats.
+    async fn transfer(seed: u64, link: LinkConfig, len: usize) -> NetworkStats {
+        let network = Network::new(seed, link);
+        let (mut client, mut server) = connection_pair(&network).await;
+
+        let data = payload(len);
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data, "seed {}", network.seed());
+        network.stats()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transfers_over_lossy_link() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(5),
+            loss: 0.05,
+            reorder: 0.05,
+            duplicate: 0.05,
+            bandwidth: Some(10 * 1024 * 1024),
+            ..Default::default()
+        };
+        let stats = transfer(1, link, 200_000).await;
+        assert!(stats.lost > 0);
+        assert!(stats.reordered > 0);
+        assert!(stats.duplicated > 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ping_waits_for_its_own_acknowledgment() {
+        let delay = Duration::from_millis(50);
+        let link = LinkConfig {
+            delay,
+            bandwidth: Some(1024 * 1024),
+            ..Default::default()
+        };
+        let network = Network::new(3, link);
+        let (mut client, mut server) = connection_pair(&network).await;
+
+        let mut stream = client.uni(1).await.unwrap();
+        let upload = tokio::spawn(async move {
+            stream.write_all(&payload(1_000_000)).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let download = tokio::spawn(async move {
+            let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+                panic!("Expected an incoming uni stream!");
+            };
+            let mut received = Vec::new();
+            incoming.read_to_end(&mut received).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Acknowledgments of the data sent before the ping keep arriving, they must not answer it.
+        let rtt = client.ping().await.unwrap();
+        assert!(rtt >= 2 * delay, "{rtt:?}");
+        upload.await.unwrap();
+        download.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_datagrams_above_mtu() {
+        let network = Network::new(2, LinkConfig::default());
+        let a = network.bind("10.0.0.1:5000".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:5000".parse().unwrap()).unwrap();
+        let to = b.local_addr().unwrap();
+        a.try_send_to(&[1; 1473], to).unwrap();
+        a.try_send_to(&[2; 1472], to).unwrap();
+
+        let mut buf = [0; 2048];
+        let mut buf = ReadBuf::new(&mut buf);
+        let from = poll_fn(|cx| b.poll_recv_from(cx, &mut buf)).await.unwrap();
+        assert_eq!(from, a.local_addr().unwrap());
+        assert_eq!(buf.filled(), [2; 1472]);
+        assert_eq!(network.stats().too_large, 1);
+    }
+}
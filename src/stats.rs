@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Snapshot of a connection's statistics, obtained with `QuicConnection::stats()`.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    /// The number of QUIC packets received.
+    pub recv: usize,
+    /// The number of QUIC packets sent.
+    pub sent: usize,
+    /// The number of QUIC packets that were lost.
+    pub lost: usize,
+    /// The number of sent QUIC packets with retransmitted data.
+    pub retrans: usize,
+    /// The number of sent bytes.
+    pub sent_bytes: u64,
+    /// The number of received bytes.
+    pub recv_bytes: u64,
+    /// The number of sent bytes that were acknowledged.
+    pub acked_bytes: u64,
+    /// The number of bytes that were lost.
+    pub lost_bytes: u64,
+    /// The number of stream bytes that were retransmitted.
+    pub stream_retrans_bytes: u64,
+    /// Statistics of every path of the connection.
+    pub paths: Vec<PathStats>,
+    /// Counters of every stream that has not been fully closed yet, keyed by stream id.
+    pub streams: HashMap<u64, StreamStats>,
+    /// ECN codepoints of the received datagrams, all zero unless `Settings::ecn` is in effect.
+    pub ecn: EcnCounts,
+    /// The largest UDP payload the connection currently sends, it grows as `Settings::pmtud`
+    /// discovers that the path carries larger packets.
+    pub max_send_udp_payload_size: usize,
+}
+
+impl ConnectionStats {
+    /// Returns the statistics of the path that is currently used to send data.
+    pub fn active_path(&self) -> Option<&PathStats> {
+        self.paths.iter().find(|path| path.active)
+    }
+}
+
+/// Statistics of a single network path.
+#[derive(Debug, Clone)]
+pub struct PathStats {
+    /// The local address of the path.
+    pub local_addr: SocketAddr,
+    /// The peer address of the path.
+    pub peer_addr: SocketAddr,
+    /// Whether the path is used to send data.
+    pub active: bool,
+    /// The number of QUIC packets received.
+    pub recv: usize,
+    /// The number of QUIC packets sent.
+    pub sent: usize,
+    /// The number of QUIC packets that were lost.
+    pub lost: usize,
+    /// The number of sent QUIC packets with retransmitted data.
+    pub retrans: usize,
+    /// The smoothed round-trip time.
+    pub rtt: Duration,
+    /// The minimum round-trip time observed.
+    pub min_rtt: Option<Duration>,
+    /// The round-trip time variation.
+    pub rttvar: Duration,
+    /// The size of the congestion window in bytes.
+    pub cwnd: usize,
+    /// The number of sent bytes.
+    pub sent_bytes: u64,
+    /// The number of received bytes.
+    pub recv_bytes: u64,
+    /// The number of bytes that were lost.
+    pub lost_bytes: u64,
+    /// The number of stream bytes that were retransmitted.
+    pub stream_retrans_bytes: u64,
+    /// The current path MTU.
+    pub pmtu: usize,
+    /// The most recent delivery rate estimate in bytes/s.
+    pub delivery_rate: u64,
+}
+
+impl From<quiche::PathStats> for PathStats {
+    fn from(stats: quiche::PathStats) -> Self {
+        Self {
+            local_addr: stats.local_addr,
+            peer_addr: stats.peer_addr,
+            active: stats.active,
+            recv: stats.recv,
+            sent: stats.sent,
+            lost: stats.lost,
+            retrans: stats.retrans,
+            rtt: stats.rtt,
+            min_rtt: stats.min_rtt,
+            rttvar: stats.rttvar,
+            cwnd: stats.cwnd,
+            sent_bytes: stats.sent_bytes,
+            recv_bytes: stats.recv_bytes,
+            lost_bytes: stats.lost_bytes,
+            stream_retrans_bytes: stats.stream_retrans_bytes,
+            pmtu: stats.pmtu,
+            delivery_rate: stats.delivery_rate,
+        }
+    }
+}
+
+/// Number of received datagrams per ECN codepoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EcnCounts {
+    /// ECN-capable transport, codepoint 0.
+    pub ect0: u64,
+    /// ECN-capable transport, codepoint 1.
+    pub ect1: u64,
+    /// Congestion experienced.
+    pub ce: u64,
+}
+
+impl EcnCounts {
+    /// Counts the ECN bits of a received datagram.
+    pub(crate) fn record(&mut self, ecn: u8) {
+        match ecn & 0b11 {
+            0b10 => self.ect0 += 1,
+            0b01 => self.ect1 += 1,
+            0b11 => self.ce += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Counters of a single stream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamStats {
+    /// Number of bytes handed to quiche for sending.
+    pub bytes_sent: u64,
+    /// Number of bytes received from the peer.
+    pub bytes_received: u64,
+    /// Total time the stream had data queued that could not be sent,
+    /// because of flow control or the congestion window.
+    pub blocked: Duration,
+}
+
+/// Per-stream counters as they are tracked by the driver.
+#[derive(Default)]
+pub(crate) struct StreamCounters {
+    pub stats: StreamStats,
+    pub blocked_since: Option<Instant>,
+}
+
+impl StreamCounters {
+    pub fn set_blocked(&mut self, blocked: bool) {
+        match (blocked, self.blocked_since) {
+            (true, None) => self.blocked_since = Some(Instant::now()),
+            (false, Some(since)) => {
+                self.stats.blocked += since.elapsed();
+                self.blocked_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the counters including the time of a block that is still ongoing.
+    pub fn snapshot(&self) -> StreamStats {
+        let mut stats = self.stats;
+        if let Some(since) = self.blocked_since {
+            stats.blocked += since.elapsed();
+        }
+        stats
+    }
+}
+
+pub(crate) fn snapshot(
+    connection: &quiche::Connection,
+    streams: &HashMap<u64, StreamCounters>,
+    ecn: EcnCounts,
+) -> ConnectionStats {
+    let stats = connection.stats();
+    ConnectionStats {
+        recv: stats.recv,
+        sent: stats.sent,
+        lost: stats.lost,
+        retrans: stats.retrans,
+        sent_bytes: stats.sent_bytes,
+        recv_bytes: stats.recv_bytes,
+        acked_bytes: stats.acked_bytes,
+        lost_bytes: stats.lost_bytes,
+        stream_retrans_bytes: stats.stream_retrans_bytes,
+        paths: connection.path_stats().map(PathStats::from).collect(),
+        streams: streams
+            .iter()
+            .map(|(stream_id, counters)| (*stream_id, counters.snapshot()))
+            .collect(),
+        ecn,
+        max_send_udp_payload_size: connection.max_send_udp_payload_size(),
+    }
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{Pmtud, Settings};
+    use crate::connection::Incoming;
+    use crate::test_util::{connection_pair, connection_pair_with, payload, settings};
+
+    #[tokio::test]
+    async fn counts_stream_bytes() {
+        let (mut client, mut server) = connection_pair().await;
+        let data = payload(30_000);
+
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(stream.stats().await.unwrap().bytes_sent, data.len() as u64);
+        assert_eq!(
+            incoming.stats().await.unwrap().bytes_received,
+            data.len() as u64
+        );
+
+        let stats = client.stats().await.unwrap();
+        assert!(stats.sent_bytes >= data.len() as u64);
+        let path = stats.active_path().unwrap();
+        assert_eq!(
+            path.peer_addr,
+            server.stats().await.unwrap().paths[0].local_addr
+        );
+    }
+
+    #[tokio::test]
+    async fn discovers_larger_path_mtu() {
+        let (mut client, mut server) = connection_pair_with(Settings {
+            pmtud: Some(Pmtud::Discover { max_size: 4000 }),
+            ..settings()
+        })
+        .await;
+        let data = payload(100_000);
+
+        let mut stream = client.uni(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let Some(Incoming::Uni(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming uni stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+
+        // Loopback carries far more than 4000 bytes, so the first probe succeeds.
+        let stats = client.stats().await.unwrap();
+        assert_eq!(stats.max_send_udp_payload_size, 4000);
+        assert_eq!(stats.active_path().unwrap().pmtu, 4000);
+    }
+
+    #[tokio::test]
+    async fn uses_fixed_path_mtu_without_probing() {
+        let (client, server) = connection_pair_with(Settings {
+            pmtud: Some(Pmtud::Fixed { size: 3000 }),
+            ..settings()
+        })
+        .await;
+
+        let stats = client.stats().await.unwrap();
+        assert_eq!(stats.max_send_udp_payload_size, 3000);
+        assert_eq!(
+            server.stats().await.unwrap().max_send_udp_payload_size,
+            3000
+        );
+    }
+}
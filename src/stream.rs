@@ -1,12 +1,18 @@
+use std::future::Future;
 use std::marker::PhantomData;
-use std::{io, task::Poll};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 use bytes::BytesMut;
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+use tokio::sync::{
+    mpsc::{
+        error::{SendError, TrySendError},
+        OwnedPermit, Receiver, Sender,
+    },
+    oneshot,
 };
 
-use crate::{error::Result, Message};
+use crate::{error::Result, stats::StreamStats, Message};
 
 pub trait UniMode {}
 pub struct Writeable;
@@ -26,9 +32,11 @@ pub(crate) trait QuicStream {
 pub struct UncheckedQuicStream {
     pub(crate) id: u64,
     #[allow(dead_code)]
-    pub(crate) rx: UnboundedReceiver<Result<Message>>,
+    pub(crate) rx: Receiver<Result<Message>>,
     #[allow(dead_code)]
-    pub(crate) tx: UnboundedSender<Message>,
+    pub(crate) tx: Sender<Message>,
+    #[allow(dead_code)]
+    pub(crate) control: Sender<Message>,
 }
 
 impl QuicStream for UncheckedQuicStream {
@@ -39,9 +47,11 @@ impl QuicStream for UncheckedQuicStream {
 
 pub struct BidiStream {
     pub(crate) id: u64,
-    pub(crate) rx: UnboundedReceiver<Result<Message>>,
-    pub(crate) tx: UnboundedSender<Message>,
+    pub(crate) rx: Receiver<Result<Message>>,
+    pub(crate) tx: StreamSender,
     pub(crate) buffer_read: BytesMut,
+    /// Set once the peer has finished its side of the stream.
+    pub(crate) fin_recv: bool,
 }
 
 impl QuicStream for BidiStream {
@@ -50,22 +60,53 @@ impl QuicStream for BidiStream {
     }
 }
 
-impl From<UncheckedQuicStream> for BidiStream {
-    fn from(stream: UncheckedQuicStream) -> Self {
+impl BidiStream {
+    pub(crate) fn new(
+        id: u64,
+        rx: Receiver<Result<Message>>,
+        tx: Sender<Message>,
+        control: Sender<Message>,
+    ) -> Self {
         Self {
-            id: stream.id,
-            rx: stream.rx,
-            tx: stream.tx,
+            id,
+            rx,
+            tx: StreamSender::new(tx, control),
             buffer_read: BytesMut::with_capacity(u16::MAX as usize),
+            fin_recv: false,
         }
     }
+
+    /// Sets the priority of this stream.
+    ///
+    /// Streams with a lower `urgency` are sent first, the default urgency is `127`.
+    /// `incremental` streams of the same urgency share the bandwidth, non-incremental ones are
+    /// sent one after the other.
+    ///
+    /// Fails with `WouldBlock` if the connection's control queue is full, see `Limits`.
+    /// Use `QuicConnection::bidi_with_priority` to open a stream with a priority.
+    pub fn set_priority(&mut self, urgency: u8, incremental: bool) -> Result<()> {
+        send_priority(&self.tx, self.id, urgency, incremental)
+    }
+
+    /// Returns the counters of this stream.
+    pub async fn stats(&self) -> Result<StreamStats> {
+        request_stats(&self.tx, self.id).await
+    }
+}
+
+impl From<UncheckedQuicStream> for BidiStream {
+    fn from(stream: UncheckedQuicStream) -> Self {
+        Self::new(stream.id, stream.rx, stream.tx, stream.control)
+    }
 }
 
 pub struct UniStream<M: UniMode> {
     pub(crate) id: u64,
-    pub(crate) rx: UnboundedReceiver<Result<Message>>,
-    pub(crate) tx: UnboundedSender<Message>,
+    pub(crate) rx: Receiver<Result<Message>>,
+    pub(crate) tx: StreamSender,
     pub(crate) buffer: BytesMut,
+    /// Set once the peer has finished its side of the stream.
+    pub(crate) fin_recv: bool,
     _ty: PhantomData<M>,
 }
 
@@ -78,15 +119,160 @@ impl<M: UniMode> QuicStream for UniStream<M> {
 impl<M: UniMode> UniStream<M> {
     pub(crate) fn new(
         id: u64,
-        rx: UnboundedReceiver<Result<Message>>,
-        tx: UnboundedSender<Message>,
+        rx: Receiver<Result<Message>>,
+        tx: Sender<Message>,
+        control: Sender<Message>,
     ) -> Self {
         Self {
             id,
             rx,
-            tx,
+            tx: StreamSender::new(tx, control),
             buffer: BytesMut::with_capacity(u16::MAX as usize),
+            fin_recv: false,
             _ty: Default::default(),
         }
     }
-}
\ No newline at end of file
+
+    /// Returns the counters of this stream.
+    pub async fn stats(&self) -> Result<StreamStats> {
+        request_stats(&self.tx, self.id).await
+    }
+}
+
+impl UniStream<Writeable> {
+    /// Sets the priority of this stream.
+    ///
+    /// Streams with a lower `urgency` are sent first, the default urgency is `127`.
+    /// `incremental` streams of the same urgency share the bandwidth, non-incremental ones are
+    /// sent one after the other.
+    ///
+    /// Fails with `WouldBlock` if the connection's control queue is full, see `Limits`.
+    /// Use `QuicConnection::uni_with_priority` to open a stream with a priority.
+    pub fn set_priority(&mut self, urgency: u8, incremental: bool) -> Result<()> {
+        send_priority(&self.tx, self.id, urgency, incremental)
+    }
+}
+
+fn send_priority(
+    tx: &StreamSender,
+    stream_id: u64,
+    urgency: u8,
+    incremental: bool,
+) -> Result<()> {
+    let message = Message::Priority {
+        stream_id,
+        urgency,
+        incremental,
+    };
+    tx.control().try_send(message).map_err(|err| match err {
+        TrySendError::Full(_) => io::ErrorKind::WouldBlock,
+        TrySendError::Closed(_) => io::ErrorKind::BrokenPipe,
+    })?;
+    Ok(())
+}
+
+async fn request_stats(tx: &StreamSender, stream_id: u64) -> Result<StreamStats> {
+    let (reply, stats) = oneshot::channel();
+    tx.control()
+        .send(Message::StreamStats { stream_id, reply })
+        .await
+        .map_err(|_| io::ErrorKind::NotConnected)?;
+    Ok(stats.await.map_err(|_| io::ErrorKind::NotConnected)?)
+}
+
+type ReserveResult = std::result::Result<OwnedPermit<Message>, SendError<()>>;
+type ReserveFuture = Pin<Box<dyn Future<Output = ReserveResult> + Send + Sync>>;
+
+/// Sending half of the bounded channel from a stream to its connection.
+///
+/// Capacity is reserved with `poll_reserve` before a message is sent, so writers wait for the
+/// connection to catch up instead of queueing data without limit.
+pub(crate) struct StreamSender {
+    tx: Sender<Message>,
+    /// The connection's control channel, which is served even while the data is held up.
+    control: Sender<Message>,
+    reserve: Option<ReserveFuture>,
+    permit: Option<OwnedPermit<Message>>,
+}
+
+impl StreamSender {
+    pub fn new(tx: Sender<Message>, control: Sender<Message>) -> Self {
+        Self {
+            tx,
+            control,
+            reserve: None,
+            permit: None,
+        }
+    }
+
+    pub fn control(&self) -> &Sender<Message> {
+        &self.control
+    }
+
+    /// Waits until the next message can be sent without blocking.
+    pub fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        let reserve = self
+            .reserve
+            .get_or_insert_with(|| Box::pin(self.tx.clone().reserve_owned()));
+        let result = ready!(reserve.as_mut().poll(cx));
+        self.reserve = None;
+        match result {
+            Ok(permit) => {
+                self.permit = Some(permit);
+                Poll::Ready(Ok(()))
+            }
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    /// Sends a message with the capacity reserved by `poll_reserve`, if there is any.
+    ///
+    /// Fails with `WouldBlock` if the channel is full.
+    pub fn try_send(&mut self, message: Message) -> io::Result<()> {
+        if let Some(permit) = self.permit.take() {
+            permit.send(message);
+            return Ok(());
+        }
+        self.tx.try_send(message).map_err(|err| match err {
+            TrySendError::Full(_) => io::ErrorKind::WouldBlock.into(),
+            TrySendError::Closed(_) => io::ErrorKind::BrokenPipe.into(),
+        })
+    }
+}
+
+/// Moves a message received from the driver into the read buffer of a stream.
+///
+/// `None` means the driver went away, which is only an error if the peer has not finished the
+/// stream yet.
+pub(crate) fn buffer_message(
+    message: Option<Result<Message>>,
+    rx: &mut Receiver<Result<Message>>,
+    buffer: &mut BytesMut,
+    fin_recv: &mut bool,
+) -> io::Result<()> {
+    match message {
+        Some(Ok(Message::Data { bytes, fin, .. })) => {
+            if fin {
+                *fin_recv = true;
+                rx.close();
+            }
+            buffer.extend_from_slice(&bytes);
+            Ok(())
+        }
+        Some(Ok(Message::Close(_id))) => {
+            *fin_recv = true;
+            rx.close();
+            Ok(())
+        }
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => Err(err.into()),
+        None if *fin_recv => Ok(()),
+        None => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Connection closed before the stream was finished!",
+        )),
+    }
+}
@@ -0,0 +1,45 @@
+use crate::config::{self, Keylog, Settings};
+use crate::connection::{QuicConnection, ToClient, ToServer};
+use crate::{QuicListener, QuicSocket};
+
+/// Settings used by all tests, running them with `SSLKEYLOGFILE` set allows decrypting captures.
+pub(crate) fn settings() -> Settings {
+    Settings {
+        keylog: Some(Keylog::Env),
+        ..Default::default()
+    }
+}
+
+/// Opens a connection over loopback and returns both of its ends.
+pub(crate) async fn connection_pair() -> (QuicConnection<ToServer>, QuicConnection<ToClient>) {
+    connection_pair_with(settings()).await
+}
+
+/// Like `connection_pair`, but both ends use `settings`.
+pub(crate) async fn connection_pair_with(
+    settings: Settings,
+) -> (QuicConnection<ToServer>, QuicConnection<ToClient>) {
+    connection_pair_with_config(config::default(), settings).await
+}
+
+/// Like `connection_pair_with`, but the server uses `config`.
+pub(crate) async fn connection_pair_with_config(
+    config: quiche::Config,
+    settings: Settings,
+) -> (QuicConnection<ToServer>, QuicConnection<ToClient>) {
+    let mut listener =
+        QuicListener::bind_with_settings("127.0.0.1:0", config, vec![7; 16], settings.clone())
+            .await
+            .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut socket = QuicSocket::bind_with_settings("127.0.0.1:0", config::default(), settings)
+        .await
+        .unwrap();
+    let (client, server) = tokio::join!(socket.connect(Some("localhost"), addr), listener.accept());
+    (client.unwrap(), server.unwrap())
+}
+
+/// Deterministic payload that makes reordered or duplicated bytes easy to spot.
+pub(crate) fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
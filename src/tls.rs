@@ -0,0 +1,434 @@
+//! Selection of the server certificate by the name a client asks for (SNI).
+//!
+//! A `quiche::Config` holds a single certificate chain. To serve several domains from one
+//! `QuicListener`, register a chain per name with a `CertResolver` and install it into the
+//! `SslContextBuilder` the config is built from:
+//!
+//! ```rust,ignore
+//! let mut resolver = CertResolver::new();
+//! resolver.add("example.com", CertifiedKey::from_pem_files("example.pem", "example.key")?);
+//! resolver.add("*.example.org", CertifiedKey::from_pem_files("org.pem", "org.key")?);
+//! let mut ctx = SslContextBuilder::new(SslMethod::tls())?;
+//! resolver.install(&mut ctx);
+//! let config = config::with_ssl_context(ctx)?;
+//! ```
+//!
+//! Certificates can be replaced while a listener is running with `QuicListener::reload_tls`,
+//! or with a `TlsReloader` that watches the certificate files.
+//!
+//! Everything but the `TlsReloader` needs the `tls` feature.
+
+#[cfg(feature = "tls")]
+use std::{collections::HashMap, fs, path::Path};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+#[cfg(feature = "tls")]
+use boring::{
+    error::ErrorStack,
+    pkey::{PKey, Private},
+    ssl::{NameType, SniError, SslAlert, SslContextBuilder, SslRef},
+    x509::X509,
+};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+
+use crate::config::Settings;
+use crate::error::Result;
+use crate::trace::event;
+
+/// A certificate chain and the private key of its leaf certificate.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub struct CertifiedKey {
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+#[cfg(feature = "tls")]
+impl CertifiedKey {
+    /// `chain` starts with the leaf certificate, followed by the intermediates.
+    pub fn new(chain: Vec<X509>, key: PKey<Private>) -> Result<Self> {
+        if chain.is_empty() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "empty certificate chain").into(),
+            );
+        }
+        Ok(Self { chain, key })
+    }
+
+    /// Parses a PEM encoded certificate chain and private key.
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Self> {
+        let chain = X509::stack_from_pem(chain).map_err(invalid_data)?;
+        let key = PKey::private_key_from_pem(key).map_err(invalid_data)?;
+        Self::new(chain, key)
+    }
+
+    /// Reads a PEM encoded certificate chain and private key from files.
+    pub fn from_pem_files(chain: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        Self::from_pem(&fs::read(chain)?, &fs::read(key)?)
+    }
+
+    /// Makes the handshake of `ssl` present this chain.
+    fn apply(&self, ssl: &mut SslRef) -> std::result::Result<(), ErrorStack> {
+        ssl.set_certificate(&self.chain[0])?;
+        for cert in &self.chain[1..] {
+            ssl.add_chain_cert(cert)?;
+        }
+        ssl.set_private_key(&self.key)
+    }
+}
+
+#[cfg(feature = "tls")]
+fn invalid_data(error: ErrorStack) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Picks a `CertifiedKey` by the server name a client sends.
+///
+/// Names are matched case-insensitively. A name starting with `*.` matches exactly one more label,
+/// `*.example.com` matches `a.example.com` but neither `example.com` nor `a.b.example.com`.
+/// Exact names take precedence over wildcards.
+/// Clients that send no name, or a name without a certificate, get the default one.
+/// Without a default their handshake fails with an `unrecognized_name` alert.
+#[cfg(feature = "tls")]
+#[derive(Clone, Default)]
+pub struct CertResolver {
+    names: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+#[cfg(feature = "tls")]
+impl CertResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `cert` to clients asking for `name`, replacing a certificate added for it before.
+    pub fn add(&mut self, name: &str, cert: CertifiedKey) -> &mut Self {
+        self.names.insert(name.to_ascii_lowercase(), Arc::new(cert));
+        self
+    }
+
+    /// Serves `cert` to clients whose name matches no other certificate.
+    pub fn set_default(&mut self, cert: CertifiedKey) -> &mut Self {
+        self.default = Some(Arc::new(cert));
+        self
+    }
+
+    /// Returns the certificate for a client that sent `server_name`.
+    pub fn resolve(&self, server_name: Option<&str>) -> Option<&CertifiedKey> {
+        server_name
+            .map(str::to_ascii_lowercase)
+            .and_then(|name| {
+                self.names.get(&name).or_else(|| {
+                    let (_, parent) = name.split_once('.')?;
+                    self.names.get(&format!("*.{parent}"))
+                })
+            })
+            .or(self.default.as_ref())
+            .map(Arc::as_ref)
+    }
+
+    /// Installs the resolver as the servername callback of `ctx`,
+    /// a certificate set on `ctx` itself is never presented.
+    pub fn install(self, ctx: &mut SslContextBuilder) {
+        ctx.set_servername_callback(move |ssl, alert| {
+            let server_name = ssl.servername(NameType::HOST_NAME).map(str::to_owned);
+            match self.resolve(server_name.as_deref()) {
+                Some(cert) => cert.apply(ssl).map_err(|_| {
+                    *alert = SslAlert::INTERNAL_ERROR;
+                    SniError::ALERT_FATAL
+                }),
+                None => {
+                    *alert = SslAlert::UNRECOGNIZED_NAME;
+                    Err(SniError::ALERT_FATAL)
+                }
+            }
+        });
+    }
+}
+
+/// The latest config handed to a listener, taken by the listener together with the sender that
+/// tells the reloader it was applied.
+pub(crate) type Reload = Arc<Mutex<Option<(quiche::Config, oneshot::Sender<()>)>>>;
+
+/// Replaces the config a `QuicListener` uses for new handshakes, see `QuicListener::reload_tls`.
+///
+/// Obtained with `QuicListener::tls_reloader`, it can be moved to other tasks.
+#[derive(Clone)]
+pub struct TlsReloader {
+    config_send: watch::Sender<Reload>,
+    settings: Settings,
+}
+
+impl TlsReloader {
+    pub(crate) fn new(config_send: watch::Sender<Reload>, settings: Settings) -> Self {
+        Self {
+            config_send,
+            settings,
+        }
+    }
+
+    /// Applies the listener's `Settings` to `config` and hands it to the listener.
+    /// Returns once the listener uses it, fails once the listener was dropped.
+    ///
+    /// Only the latest config is kept, if another reload replaces `config` before the listener
+    /// took it, `config` is never used.
+    pub async fn reload(&self, mut config: quiche::Config) -> Result<()> {
+        self.settings.apply(&mut config)?;
+        let (applied_send, applied) = oneshot::channel();
+        self.config_send
+            .send(Arc::new(Mutex::new(Some((config, applied_send)))))
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        if applied.await.is_err() && self.config_send.is_closed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(())
+    }
+
+    /// Spawns a task that calls `load` and reloads its config whenever one of `paths` was modified.
+    ///
+    /// The modification times are checked every `interval`. If `load` fails, e.g. because the key
+    /// was not written yet, the previous config stays in use and `load` is retried every
+    /// `interval` until it succeeds. The task ends once the listener was dropped.
+    pub fn watch<F>(self, paths: Vec<PathBuf>, interval: Duration, mut load: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Result<quiche::Config> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut modified = modified_times(&paths).await;
+            let mut pending = false;
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            while !self.config_send.is_closed() {
+                ticks.tick().await;
+                let current = modified_times(&paths).await;
+                if current != modified {
+                    modified = current;
+                    pending = true;
+                }
+                if !pending {
+                    continue;
+                }
+                let reloaded = match load() {
+                    Ok(config) => self.reload(config).await,
+                    Err(error) => Err(error),
+                };
+                match reloaded {
+                    Ok(()) => pending = false,
+                    Err(error) => event!(warn, "Failed to reload config", error = error),
+                }
+            }
+        })
+    }
+}
+
+/// Missing files are recorded as `None`, so that creating them counts as a modification.
+async fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(paths.len());
+    for path in paths {
+        let metadata = tokio::fs::metadata(path).await;
+        times.push(metadata.and_then(|meta| meta.modified()).ok());
+    }
+    times
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use boring::ssl::{SslContextBuilder, SslMethod};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{CertResolver, CertifiedKey};
+    use crate::config::{self, generate_local_certificate};
+    use crate::connection::Incoming;
+    use crate::error::Result;
+    use crate::test_util::settings;
+    use crate::{QuicListener, QuicSocket};
+
+    fn cert() -> CertifiedKey {
+        let (key, chain) = generate_local_certificate();
+        CertifiedKey::from_pem(&chain, &key).unwrap()
+    }
+
+    /// A config that only serves `name`.
+    fn config_for(name: &str) -> quiche::Config {
+        let mut resolver = CertResolver::new();
+        resolver.add(name, cert());
+        let mut ctx = SslContextBuilder::new(SslMethod::tls()).unwrap();
+        resolver.install(&mut ctx);
+        config::with_ssl_context(ctx).unwrap()
+    }
+
+    #[test]
+    fn resolves_by_server_name() {
+        let mut resolver = CertResolver::new();
+        resolver
+            .add("example.com", cert())
+            .add("*.example.com", cert());
+        let exact: *const CertifiedKey = resolver.resolve(Some("Example.COM")).unwrap();
+        let wildcard: *const CertifiedKey = resolver.resolve(Some("a.example.com")).unwrap();
+
+        assert!(!std::ptr::eq(exact, wildcard));
+        assert!(resolver.resolve(Some("a.b.example.com")).is_none());
+        assert!(resolver.resolve(None).is_none());
+
+        resolver.set_default(cert());
+        let default: *const CertifiedKey = resolver.resolve(None).unwrap();
+        assert!(std::ptr::eq(
+            resolver.resolve(Some("a.b.example.com")).unwrap(),
+            default
+        ));
+        assert!(std::ptr::eq(
+            resolver.resolve(Some("a.example.com")).unwrap(),
+            wildcard
+        ));
+    }
+
+    /// Every connection gets its own socket, connections sharing one would take each other's
+    /// datagrams.
+    async fn socket() -> QuicSocket {
+        QuicSocket::bind_with_settings("127.0.0.1:0", config::default(), settings())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_server_name() {
+        let mut listener = QuicListener::bind_with_settings(
+            "127.0.0.1:0",
+            config_for("a.test"),
+            vec![7; 16],
+            settings(),
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut socket_a = socket().await;
+        let (client, server) =
+            tokio::join!(socket_a.connect(Some("a.test"), addr), listener.accept());
+        assert_eq!(
+            client.unwrap().handshake_info().server_name.as_deref(),
+            Some("a.test")
+        );
+        assert_eq!(
+            server.unwrap().handshake_info().server_name.as_deref(),
+            Some("a.test")
+        );
+
+        let mut socket_b = socket().await;
+        let (client, server) =
+            tokio::join!(socket_b.connect(Some("b.test"), addr), listener.accept());
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_existing_connections() {
+        let mut listener = QuicListener::bind_with_settings(
+            "127.0.0.1:0",
+            config_for("a.test"),
+            vec![7; 16],
+            settings(),
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut socket_a = socket().await;
+        let (client, server) =
+            tokio::join!(socket_a.connect(Some("a.test"), addr), listener.accept());
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        listener.reload_tls(config_for("b.test")).await.unwrap();
+
+        let mut socket_old = socket().await;
+        let (old, _) = tokio::join!(socket_old.connect(Some("a.test"), addr), listener.accept());
+        assert!(old.is_err());
+        let mut socket_new = socket().await;
+        let (new, accepted) =
+            tokio::join!(socket_new.connect(Some("b.test"), addr), listener.accept());
+        new.unwrap();
+        accepted.unwrap();
+
+        let mut stream = client.bidi(0).await.unwrap();
+        stream.write_all(b"still there").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let Some(Incoming::Bidi(mut stream)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"still there");
+    }
+
+    /// Writes a new certificate to `dir`, together with the `name` it is served for.
+    fn write_cert(dir: &Path, name: &str) {
+        let (key, chain) = generate_local_certificate();
+        std::fs::write(dir.join("cert.pem"), chain).unwrap();
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+        std::fs::write(dir.join("name"), name).unwrap();
+    }
+
+    /// A config that serves the certificate `write_cert` wrote to `dir`.
+    fn load(dir: &Path) -> Result<quiche::Config> {
+        let name = std::fs::read_to_string(dir.join("name"))?;
+        let cert = CertifiedKey::from_pem_files(dir.join("cert.pem"), dir.join("key.pem"))?;
+        let mut resolver = CertResolver::new();
+        resolver.add(&name, cert);
+        let mut ctx = SslContextBuilder::new(SslMethod::tls()).unwrap();
+        resolver.install(&mut ctx);
+        config::with_ssl_context(ctx)
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_rewritten_certificate() {
+        let dir = std::env::temp_dir().join(format!("tokio-quicker-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_cert(&dir, "a.test");
+        let mut listener = QuicListener::bind_with_settings(
+            "127.0.0.1:0",
+            load(&dir).unwrap(),
+            vec![7; 16],
+            settings(),
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let paths: Vec<PathBuf> = ["cert.pem", "key.pem", "name"]
+            .iter()
+            .map(|file| dir.join(file))
+            .collect();
+        let watched = dir.clone();
+        let watch = listener
+            .tls_reloader()
+            .watch(paths, Duration::from_millis(50), move || load(&watched));
+        // Lets the watcher record the modification times of the first certificate. Some file
+        // systems only keep whole seconds, so the rewrite has to happen in a later one.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        write_cert(&dir, "b.test");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut socket_new = socket().await;
+        let (new, accepted) =
+            tokio::join!(socket_new.connect(Some("b.test"), addr), listener.accept());
+        new.unwrap();
+        accepted.unwrap();
+        let mut socket_old = socket().await;
+        let (old, _) = tokio::join!(socket_old.connect(Some("a.test"), addr), listener.accept());
+        assert!(old.is_err());
+
+        watch.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Diagnostics that go through `tracing` if the `tracing` feature is enabled and `log` otherwise.
+//!
+//! With `tracing` the listener, every connection and every stream get their own span,
+//! so the output of many concurrent connections can be filtered by `trace_id` or `peer`.
+//! Without it the same events are emitted as log lines with the fields appended as `key=value`.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// Stand-in for `tracing::Span` if the `tracing` feature is disabled.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn current() -> Self {
+        Span
+    }
+
+    pub fn entered(self) -> Self {
+        self
+    }
+
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+}
+
+/// Emits an event with structured fields, e.g.
+/// `event!(warn, "Dropped packet", reason = "unknown connection", from = from)`.
+///
+/// All field values have to implement `Display`.
+macro_rules! event {
+    (error, $($body:tt)+) => { $crate::trace::event!(@emit ERROR, Error, $($body)+) };
+    (warn, $($body:tt)+) => { $crate::trace::event!(@emit WARN, Warn, $($body)+) };
+    (info, $($body:tt)+) => { $crate::trace::event!(@emit INFO, Info, $($body)+) };
+    (debug, $($body:tt)+) => { $crate::trace::event!(@emit DEBUG, Debug, $($body)+) };
+    (trace, $($body:tt)+) => { $crate::trace::event!(@emit TRACE, Trace, $($body)+) };
+    (@emit $tracing_level:ident, $log_level:ident, $message:literal $(, $key:ident = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$tracing_level, $($key = %$value,)* $message);
+        #[cfg(not(feature = "tracing"))]
+        log::log!(
+            log::Level::$log_level,
+            concat!($message $(, " ", stringify!($key), "={}")*),
+            $($value),*
+        );
+    }};
+}
+
+/// Creates a span with structured fields, e.g. `span!(info, "connection", trace_id = id)`.
+///
+/// All field values have to implement `Display`.
+macro_rules! span {
+    (info, $($body:tt)+) => { $crate::trace::span!(@create INFO, $($body)+) };
+    (debug, $($body:tt)+) => { $crate::trace::span!(@create DEBUG, $($body)+) };
+    (@create $level:ident, $name:literal $(, $key:ident = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::span!(tracing::Level::$level, $name $(, $key = %$value)*);
+        #[cfg(not(feature = "tracing"))]
+        let span = {
+            $(let _ = &$value;)*
+            $crate::trace::Span
+        };
+        span
+    }};
+}
+
+pub(crate) use {event, span};
+
+/// Records a field that only implements `Debug`.
+pub(crate) struct Dbg<T>(pub T);
+
+impl<T: Debug> Display for Dbg<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/// Runs `future` inside of `span`.
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F: Future>(future: F, span: Span) -> impl Future<Output = F::Output> {
+    tracing::Instrument::instrument(future, span)
+}
+
+/// Runs `future` inside of `span`.
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F: Future>(future: F, _span: Span) -> impl Future<Output = F::Output> {
+    future
+}
@@ -0,0 +1,437 @@
+//! The datagram transport that listeners and sockets exchange their packets over.
+//!
+//! By default this is a [`UdpSocket`], but anything implementing [`DatagramTransport`] can be
+//! passed to `QuicListener::with_transport` and `QuicSocket::with_transport`.
+//! [`MemoryTransport`] links a client and a server within the process, so tests can run
+//! without touching the network stack. [`FaultInjector`] wraps another transport
+//! to run an endpoint with injected loss, latency and partitions.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Sends and receives datagrams on behalf of a listener or a socket.
+///
+/// All methods take `&self`, as the transport of a listener is shared by all of its connections.
+/// Datagrams may get lost, just like with UDP, QUIC takes care of retransmitting them.
+pub trait DatagramTransport: Send + Sync + 'static {
+    /// Returns the address datagrams are sent from.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Sends a datagram to `to`, or registers the task to be woken once that is possible.
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        to: SocketAddr,
+    ) -> Poll<io::Result<()>>;
+
+    /// Sends a datagram to `to` if that is possible without waiting.
+    ///
+    /// Used for the stateless responses of a listener, which are dropped instead of holding
+    /// up all other connections.
+    fn try_send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<()>;
+
+    /// Receives a datagram into `buf` and returns its sender,
+    /// or registers the task to be woken once a datagram arrives.
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>>;
+
+    /// Returns the UDP socket that the transport sends through unchanged, if there is one.
+    ///
+    /// This enables the batched socket path of the `udp-offload` feature, including ECN
+    /// and `Pacing::Kernel`. A transport that alters the datagrams must return `None`.
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        None
+    }
+}
+
+impl DatagramTransport for UdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        to: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        UdpSocket::poll_send_to(self, cx, buf, to).map_ok(|_| ())
+    }
+
+    fn try_send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+        UdpSocket::try_send_to(self, buf, to).map(|_| ())
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+/// Datagrams that are queued for one end of a `MemoryTransport`, further ones are dropped.
+const MEMORY_QUEUE_CAPACITY: usize = 1024;
+
+/// One end of an in-memory link between two addresses, created with [`MemoryTransport::pair`].
+///
+/// Datagrams to any address but the other end's are dropped,
+/// as are datagrams that find the other end's queue full.
+pub struct MemoryTransport {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    inbox: Arc<Mutex<Inbox>>,
+    outbox: Arc<Mutex<Inbox>>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    datagrams: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// The task waiting for a datagram.
+    waker: Option<Waker>,
+}
+
+impl MemoryTransport {
+    /// Creates both ends of a link, the first one with the address `a`, the second one with `b`.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+        let a_inbox = Arc::new(Mutex::new(Inbox::default()));
+        let b_inbox = Arc::new(Mutex::new(Inbox::default()));
+        (
+            Self {
+                local_addr: a,
+                peer_addr: b,
+                inbox: a_inbox.clone(),
+                outbox: b_inbox.clone(),
+            },
+            Self {
+                local_addr: b,
+                peer_addr: a,
+                inbox: b_inbox,
+                outbox: a_inbox,
+            },
+        )
+    }
+}
+
+impl DatagramTransport for MemoryTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn poll_send_to(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        to: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(self.try_send_to(buf, to))
+    }
+
+    fn try_send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+        if to != self.peer_addr {
+            return Ok(());
+        }
+        let mut outbox = self.outbox.lock().unwrap();
+        if outbox.datagrams.len() < MEMORY_QUEUE_CAPACITY {
+            outbox.datagrams.push_back((self.local_addr, buf.to_vec()));
+            if let Some(waker) = outbox.waker.take() {
+                waker.wake();
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        let mut inbox = self.inbox.lock().unwrap();
+        match inbox.datagrams.pop_front() {
+            Some((from, datagram)) => {
+                // Like UDP, whatever does not fit into the buffer is cut off.
+                let len = datagram.len().min(buf.remaining());
+                buf.put_slice(&datagram[..len]);
+                Poll::Ready(Ok(from))
+            }
+            None => {
+                inbox.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// The faults a `FaultInjector` applies, they can be changed while it is in use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    /// Probability with which a datagram is dropped, applied to both directions.
+    pub loss: f64,
+    /// Delay that is added to every received datagram.
+    pub delay: Duration,
+    /// Upper bound of a random delay that is added on top of `delay`.
+    pub jitter: Duration,
+    /// Drops all datagrams in both directions during recurring windows.
+    pub partition: Option<Partition>,
+    /// Drops all datagrams in both directions.
+    pub blackhole: bool,
+}
+
+/// Recurring windows in which no datagrams get through.
+///
+/// The window covers the last `duration` of every `every`, counted from when the
+/// `FaultInjector` was created, so every partition is preceded by working connectivity.
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    /// Time from the start of one partition to the start of the next.
+    pub every: Duration,
+    /// How long each partition lasts.
+    pub duration: Duration,
+}
+
+/// Wraps a transport, e.g. the `UdpSocket` of a staging service, to inject faults into its traffic.
+///
+/// Keep a clone of the `Arc` passed to `QuicListener::with_transport` or
+/// `QuicSocket::with_transport` to change the faults at runtime with `set_faults`.
+/// The batched socket path of the `udp-offload` feature is not used for a wrapped socket.
+pub struct FaultInjector<T = UdpSocket> {
+    inner: T,
+    faults: Mutex<Faults>,
+    created: Instant,
+    delayed: Mutex<Delayed>,
+}
+
+struct Delayed {
+    /// Received datagrams ordered by when they are released.
+    datagrams: BinaryHeap<Reverse<(Instant, u64, SocketAddr, Vec<u8>)>>,
+    /// Keeps datagrams that are released at the same time in the order they arrived.
+    sequence: u64,
+    /// Wakes the receiving task when the next datagram is released.
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<T: DatagramTransport> FaultInjector<T> {
+    /// Has to be called from within a tokio runtime.
+    pub fn new(inner: T, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(faults),
+            created: Instant::now(),
+            delayed: Mutex::new(Delayed {
+                datagrams: BinaryHeap::new(),
+                sequence: 0,
+                sleep: Box::pin(sleep_until(Instant::now())),
+            }),
+        }
+    }
+
+    /// Returns the faults that are currently applied.
+    pub fn faults(&self) -> Faults {
+        *self.faults.lock().unwrap()
+    }
+
+    /// Replaces the faults, datagrams that are already delayed keep their release time.
+    pub fn set_faults(&self, faults: Faults) {
+        *self.faults.lock().unwrap() = faults;
+    }
+
+    /// Returns the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Whether the next datagram is dropped.
+    fn drop_datagram(&self, faults: &Faults) -> bool {
+        if faults.blackhole {
+            return true;
+        }
+        if let Some(Partition { every, duration }) = faults.partition {
+            let elapsed = self.created.elapsed().as_nanos() % every.as_nanos().max(1);
+            if elapsed >= every.saturating_sub(duration).as_nanos() {
+                return true;
+            }
+        }
+        faults.loss > 0.0 && rand::thread_rng().gen_bool(faults.loss)
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for FaultInjector<T> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        to: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        if self.drop_datagram(&self.faults()) {
+            return Poll::Ready(Ok(()));
+        }
+        self.inner.poll_send_to(cx, buf, to)
+    }
+
+    fn try_send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+        if self.drop_datagram(&self.faults()) {
+            return Ok(());
+        }
+        self.inner.try_send_to(buf, to)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        let mut delayed = self.delayed.lock().unwrap();
+        loop {
+            if let Some(Reverse((release, ..))) = delayed.datagrams.peek() {
+                if *release <= Instant::now() {
+                    let Reverse((_, _, from, datagram)) = delayed.datagrams.pop().unwrap();
+                    let len = datagram.len().min(buf.remaining());
+                    buf.put_slice(&datagram[..len]);
+                    return Poll::Ready(Ok(from));
+                }
+            }
+
+            let Poll::Ready(from) = self.inner.poll_recv_from(cx, buf) else {
+                let Some(&Reverse((release, ..))) = delayed.datagrams.peek() else {
+                    return Poll::Pending;
+                };
+                let sleep = &mut delayed.sleep;
+                if sleep.deadline() != release {
+                    sleep.as_mut().reset(release);
+                }
+                ready!(sleep.as_mut().poll(cx));
+                continue;
+            };
+            let from = from?;
+            let faults = self.faults();
+            if self.drop_datagram(&faults) {
+                buf.clear();
+                continue;
+            }
+            let delay = faults.delay + faults.jitter.mul_f64(rand::thread_rng().gen::<f64>());
+            if delay.is_zero() {
+                return Poll::Ready(Ok(from));
+            }
+            delayed.sequence += 1;
+            let entry = (
+                Instant::now() + delay,
+                delayed.sequence,
+                from,
+                buf.filled().to_vec(),
+            );
+            delayed.datagrams.push(Reverse(entry));
+            buf.clear();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "key-gen"))]
+mod test {
+    use std::sync::Arc;
+
+    use std::future::poll_fn;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+    use tokio::time::Instant;
+
+    use super::{DatagramTransport, FaultInjector, Faults, MemoryTransport};
+    use crate::config;
+    use crate::connection::Incoming;
+    use crate::test_util::{payload, settings};
+    use crate::{QuicListener, QuicSocket};
+
+    #[tokio::test]
+    async fn connects_in_memory() {
+        let client_addr = "10.0.0.1:5000".parse().unwrap();
+        let server_addr = "10.0.0.2:4433".parse().unwrap();
+        let (client_io, server_io) = MemoryTransport::pair(client_addr, server_addr);
+        let mut listener = QuicListener::with_transport(
+            Arc::new(server_io),
+            config::default(),
+            vec![7; 16],
+            settings(),
+        )
+        .unwrap();
+        let mut socket =
+            QuicSocket::with_transport(Arc::new(client_io), config::default(), settings()).unwrap();
+        let (client, server) = tokio::join!(
+            socket.connect(Some("localhost"), server_addr),
+            listener.accept()
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let data = payload(100_000);
+        let mut stream = client.bidi(1).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let Some(Incoming::Bidi(mut incoming)) = server.incoming().await else {
+            panic!("Expected an incoming bidi stream!");
+        };
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        assert_eq!(
+            server.stats().await.unwrap().paths[0].peer_addr,
+            client_addr
+        );
+    }
+
+    #[tokio::test]
+    async fn injects_faults() {
+        let (a, b) = MemoryTransport::pair(
+            "10.0.0.1:5000".parse().unwrap(),
+            "10.0.0.2:5000".parse().unwrap(),
+        );
+        let to = b.local_addr().unwrap();
+        let blackhole = Faults {
+            blackhole: true,
+            ..Default::default()
+        };
+        let delay = Faults {
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let (a, b) = (
+            FaultInjector::new(a, blackhole),
+            FaultInjector::new(b, delay),
+        );
+
+        a.try_send_to(b"dropped", to).unwrap();
+        a.set_faults(Faults::default());
+        a.try_send_to(b"delayed", to).unwrap();
+
+        let mut buf = [0; 16];
+        let mut buf = ReadBuf::new(&mut buf);
+        let start = Instant::now();
+        poll_fn(|cx| b.poll_recv_from(cx, &mut buf)).await.unwrap();
+        assert_eq!(buf.filled(), b"delayed");
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}